@@ -0,0 +1,14 @@
+// Generates the gRPC server code from proto/ when the `grpc` feature is on.
+// protoc comes from protoc-bin-vendored, so no system install is needed.
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/market_data.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/market_data.proto"], &["proto"])
+            .expect("failed to compile proto/market_data.proto");
+    }
+}
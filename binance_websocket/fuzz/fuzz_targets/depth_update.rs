@@ -0,0 +1,11 @@
+// Run with: cargo +nightly fuzz run depth_update
+// Input is a JSON snapshot line followed by JSON depth update lines.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../../src/main.rs"]
+mod client;
+
+fuzz_target!(|data: &[u8]| client::fuzz_apply_updates(data));
@@ -0,0 +1,140 @@
+// gRPC view of the cache for consumers in other languages; the service and its
+// messages are defined in proto/market_data.proto, from which clients can be
+// generated. Reads go through the same staleness checks as the Rust API, and
+// the bookTicker stream is fed from the cache's market event broadcast.
+use super::{BookTickerData, MarketDataCache, MarketEvent, TradeData, BROADCAST_DEPTH, ORDER_BOOK_DEPTH};
+use futures_util::Stream;
+use rust_decimal::Decimal;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tonic::{Request, Response, Status};
+use tracing::{error, info, warn};
+
+mod proto {
+    tonic::include_proto!("market_data.v1");
+}
+
+use proto::market_data_server::{MarketData, MarketDataServer};
+
+impl From<BookTickerData> for proto::BookTicker {
+    fn from(ticker: BookTickerData) -> Self {
+        Self {
+            symbol: ticker.symbol.to_uppercase(),
+            update_id: ticker.update_id,
+            bid_price: ticker.bid_price.to_string(),
+            bid_qty: ticker.bid_qty.to_string(),
+            ask_price: ticker.ask_price.to_string(),
+            ask_qty: ticker.ask_qty.to_string(),
+        }
+    }
+}
+
+impl From<TradeData> for proto::Trade {
+    fn from(trade: TradeData) -> Self {
+        Self {
+            symbol: trade.symbol.to_uppercase(),
+            trade_id: trade.trade_id,
+            price: trade.price.to_string(),
+            quantity: trade.quantity.to_string(),
+            event_time: trade.event_time,
+            trade_time: trade.trade_time,
+            is_buyer_market_maker: trade.is_buyer_market_maker,
+        }
+    }
+}
+
+fn levels(side: Vec<(Decimal, Decimal)>) -> Vec<proto::Level> {
+    side.into_iter()
+        .map(|(price, qty)| proto::Level { price: price.to_string(), quantity: qty.to_string() })
+        .collect()
+}
+
+pub struct MarketDataService {
+    cache: Arc<MarketDataCache>,
+}
+
+impl MarketDataService {
+    pub fn new(cache: Arc<MarketDataCache>) -> Self {
+        Self { cache }
+    }
+
+    pub async fn run(self, addr: SocketAddr) {
+        info!("Serving market data over gRPC on {}", addr);
+        let result = tonic::transport::Server::builder()
+            .add_service(MarketDataServer::new(self))
+            .serve(addr)
+            .await;
+        if let Err(e) = result {
+            error!("gRPC server on {} stopped: {}", addr, e);
+        }
+    }
+}
+
+type BookTickerStream = Pin<Box<dyn Stream<Item = Result<proto::BookTicker, Status>> + Send>>;
+
+// Book tickers from the broadcast for the requested symbols (uppercase, all if
+// empty). A subscriber that falls behind skips what it missed rather than
+// ending the stream, as the other broadcast consumers do.
+fn book_ticker_stream(events: broadcast::Receiver<MarketEvent>, symbols: HashSet<String>) -> BookTickerStream {
+    Box::pin(futures_util::stream::unfold((events, symbols), |(mut events, symbols)| async move {
+        loop {
+            match events.recv().await {
+                Ok(MarketEvent::BookTicker(ticker)) => {
+                    // Only tickers that are sent get converted
+                    if symbols.is_empty() || symbols.contains(&ticker.symbol.to_uppercase()) {
+                        return Some((Ok(ticker.into()), (events, symbols)));
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("gRPC bookTicker subscriber lagged, {} market events missed", skipped);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }))
+}
+
+#[tonic::async_trait]
+impl MarketData for MarketDataService {
+    type SubscribeBookTickerStream = BookTickerStream;
+
+    async fn subscribe_book_ticker(
+        &self,
+        request: Request<proto::SubscribeBookTickerRequest>,
+    ) -> Result<Response<Self::SubscribeBookTickerStream>, Status> {
+        let symbols = request.into_inner().symbols.iter().map(|s| s.to_uppercase()).collect();
+        Ok(Response::new(book_ticker_stream(self.cache.subscribe(), symbols)))
+    }
+
+    async fn get_depth(&self, request: Request<proto::GetDepthRequest>) -> Result<Response<proto::Depth>, Status> {
+        let request = request.into_inner();
+        // Capped at the deepest REST snapshot, so one call can't copy out an unbounded book
+        let levels_per_side = match request.levels {
+            0 => BROADCAST_DEPTH,
+            n => (n as usize).min(ORDER_BOOK_DEPTH),
+        };
+        let symbol = request.symbol.to_uppercase();
+        let depth = self.cache.with_order_book(&symbol, |book| proto::Depth {
+            symbol: symbol.clone(),
+            bids: levels(book.top_bids(levels_per_side)),
+            asks: levels(book.top_asks(levels_per_side)),
+            last_update_id: book.last_update_id,
+            synced: book.synced,
+        });
+        depth
+            .map(Response::new)
+            .ok_or_else(|| Status::not_found(format!("no order book for {}", symbol)))
+    }
+
+    async fn get_trade(&self, request: Request<proto::GetTradeRequest>) -> Result<Response<proto::Trade>, Status> {
+        let symbol = request.into_inner().symbol;
+        self.cache
+            .last_trade(&symbol)
+            .map(|trade| Response::new(trade.into()))
+            .ok_or_else(|| Status::not_found(format!("no trade for {}", symbol)))
+    }
+}
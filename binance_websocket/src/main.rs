@@ -2,39 +2,1110 @@ use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
+use tokio::sync::broadcast;
+use tokio_tungstenite::{connect_async_tls_with_config, tungstenite::protocol::{CloseFrame, Message, WebSocketConfig}, Connector, MaybeTlsStream, WebSocketStream};
 use url::Url;
-use dashmap::DashMap;
-use tracing::{info, error, warn, debug, instrument};
+use dashmap::{DashMap, DashSet};
+use tracing::{info, error, warn, debug, instrument, Instrument};
 use tracing_subscriber::{fmt, EnvFilter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use std::time::Duration;
 use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
 use thiserror::Error;
 use reqwest::Client as HttpClient;
-use std::cmp::Ordering;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering as AtomicOrdering};
+use std::time::SystemTime;
+
+#[cfg(feature = "grpc")]
+mod grpc;
+mod message_workers;
+#[cfg(feature = "otel")]
+mod otel;
+#[cfg(feature = "parquet")]
+mod parquet_sink;
+mod trade_bucketizer;
+mod udp_sink;
 
 // Configuration constants
 const BINANCE_WS_URL: &str = "wss://fstream.binance.com/stream";
 const BINANCE_API_URL: &str = "https://fapi.binance.com";
+const BINANCE_COIN_M_WS_URL: &str = "wss://dstream.binance.com/stream";
+const BINANCE_COIN_M_API_URL: &str = "https://dapi.binance.com";
 const RECONNECT_DELAY_MS: u64 = 1000;
+const CLOSE_CODE_BACKOFF_SECS: u64 = 60; // Cooldown after close codes configured for long backoff
 const MAX_RECONNECT_ATTEMPTS: u8 = 5;
 const BATCH_SIZE: usize = 50;
 const BATCH_INTERVAL_MS: u64 = 100;
 const ORDER_BOOK_DEPTH: usize = 1000; // Depth to fetch for initial snapshot
+const SNAPSHOT_DEPTH_LIMITS: &[usize] = &[5, 10, 20, 50, 100, 500, 1000]; // Depths the REST endpoint accepts
+const SNAPSHOT_FETCH_DELAY_MS: u64 = 500; // Pause after each snapshot fetch, per concurrent fetch slot
+const REST_WEIGHT_BACKOFF_1M: u32 = 2000; // Used request weight at which snapshot fetches wait for the next minute
+const RATE_LIMIT_BACKOFF_SECS: u64 = 60; // Wait after a 429 that carries no Retry-After
+const IP_BAN_BACKOFF_SECS: u64 = 600; // Wait after a 418 (IP ban) that carries no Retry-After
+const EVENT_LOG_CAPACITY: usize = 256; // Operational events retained for post-mortem debugging
+const WARMUP_PERIOD_SECS: u64 = 30; // Failures during startup don't count toward MAX_RECONNECT_ATTEMPTS
+const STABLE_CONNECTION_SECS: u64 = 60; // Uptime after which the reconnect counter resets
+const MAX_STREAMS_PER_CONNECTION: usize = 200; // Binance combined stream limit
+const MAX_STREAM_URL_LEN: usize = 8192; // Keep the combined stream URL within common request-line limits
+const MAX_MESSAGE_SIZE: usize = 4 << 20; // Largest WebSocket message (and frame) accepted, in bytes
+const COMPACTION_MAX_DISTANCE_BPS: u32 = 1000; // Levels beyond 10% from mid are trimmed on compaction
+const CHECKPOINT_INTERVAL_SECS: u64 = 60; // How often order books are checkpointed to disk
+const CHECKPOINT_MAX_AGE_SECS: u64 = 30; // Checkpoints younger than this skip the startup snapshot fetch
+const BROADCAST_CAPACITY: usize = 1024; // Buffered market events per subscriber
+const BROADCAST_DEPTH: usize = 20; // Levels per side in broadcast order book events
+const INTER_ARRIVAL_WINDOW: usize = 1024; // Recent gaps kept per stream for percentile estimates
+const FLICKER_IDLE_SECS: u64 = 300; // Books without an update for this long drop out of flicker tracking
+const PAUSE_BUFFER_CAPACITY: usize = 100_000; // Frames held while paused before dropping the oldest
+const REFRESH_BUFFER_CAPACITY: usize = 10_000; // Depth updates held per book while its snapshot refetch is in flight
+const DIVERGENCE_REFETCH_COOLDOWN_SECS: u64 = 30; // Min gap between bookTicker divergence refetches of one book
+const SYNC_CHECK_INTERVAL_MS: u64 = 1000; // How often unsynced books are checked against sync_deadline
+const EMPTY_SIDE_CHECK_INTERVAL_MS: u64 = 1000; // How often synced books are checked for an empty side
+const DEPTH_DOWNGRADE_WINDOW_SECS: u64 = 600; // Window over which a book's desyncs count toward depth_downgrade_after
+const PARTIAL_DEPTH_STREAM: &str = "depth20@100ms"; // Stream a downgraded symbol's depth book is kept from
+const FUNDING_REFRESH_CHECK_INTERVAL_MS: u64 = 1000; // How often funding-aligned snapshot refreshes are checked for being due
+const MAX_SYNC_RETRIES: u32 = 3; // Fresh snapshots fetched for a book that misses its sync deadline
+const THINNESS_LEVELS: usize = 20; // Levels per side summed into a book's notional for thinness checks
+const THINNESS_WINDOW: usize = 60; // Notional samples kept per symbol for the thinness baseline
+const SPREAD_HISTORY_SECS: u64 = 300; // How long sampled spreads are kept for time_weighted_spread
+const PARQUET_ROTATE_INTERVAL_SECS: u64 = 3600; // Age at which a Parquet output file is rotated
+const PARQUET_MAX_ROWS: usize = 1_000_000; // Rows at which a Parquet output file is rotated
+const INGRESS_SAMPLE_INTERVAL_SECS: u64 = 1; // How often the received bytes/messages per second are recomputed
+const SEQUENCE_RESET_THRESHOLD: u64 = 10_000_000; // Backward jump in update ids treated as an exchange sequence reset
+const TRADE_ARCHIVE_INTERVAL_SECS: u64 = 10; // How often buffered trades are appended to the trade archive
+const TRADE_BUCKET_CHANNEL_CAPACITY: usize = 1024; // Unread 1-second trade aggregates before the bucketizer waits
+const MESSAGE_WORKER_QUEUE_CAPACITY: usize = 1024; // Frames queued per message worker before the read loop waits
+const INVALID_FRAME_SNIPPET_CHARS: usize = 120; // Leading characters of an unparseable frame included in its warning
+const RESYNC_HISTOGRAM_BOUNDS: &[Duration] = &[
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+    Duration::from_secs(30),
+    Duration::from_secs(60),
+    Duration::from_secs(300),
+];
+const VERIFY_WINDOW_SECS: u64 = 30; // How long --verify maintains the book before diffing
+const VERIFY_CATCH_UP_SECS: u64 = 10; // Max wait for the book to reach the reference snapshot's update id
+const VERIFY_DEPTH: usize = 50; // Levels per side compared by --verify
+const VERIFY_MAX_MISMATCH_PCT: i64 = 5; // Tolerated share of mismatched levels, in percent
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 enum WebSocketError {
     #[error("Connection error: {0}")]
     ConnectionError(#[from] url::ParseError),
+    // Boxed to keep the Result small; tungstenite's error is large
     #[error("WebSocket error: {0}")]
-    WebSocketError(#[from] tokio_tungstenite::tungstenite::Error),
+    WebSocketError(Box<tokio_tungstenite::tungstenite::Error>),
     #[error("Max reconnect attempts reached")]
     MaxReconnectAttempts,
     #[error("HTTP error: {0}")]
     HttpError(#[from] reqwest::Error),
     #[error("JSON error: {0}")]
     JsonError(#[from] serde_json::Error),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Config error: {0}")]
+    ConfigError(String),
+    // 429 (rate limit) or 418 (IP ban) from the REST API; the fetch already waited out retry_after
+    #[error("REST API rate limited (HTTP {status}), backed off for {retry_after:?}")]
+    RateLimited { status: u16, retry_after: Duration },
+    #[error("Unknown symbols: {0:?}")]
+    UnknownSymbols(Vec<String>),
+    // Only with unknown_stream_policy = "error"
+    #[error("Unknown or unhandled stream type: {0}")]
+    UnknownStream(String),
+    // Rejected before it was buffered in full; the connection can't continue after it
+    #[error("WebSocket message of {size} bytes exceeds max_message_size of {max_size}")]
+    MessageTooLarge { size: usize, max_size: usize },
+    #[error("TLS error: {0}")]
+    TlsError(#[from] native_tls::Error),
+    #[error("Bincode error: {0}")]
+    BincodeError(#[from] bincode::Error),
+    #[error("Order book error: {0}")]
+    OrderBookError(#[from] OrderBookError),
+    #[cfg(feature = "parquet")]
+    #[error("Parquet error: {0}")]
+    ParquetError(#[from] parquet::errors::ParquetError),
+    #[cfg(feature = "otel")]
+    #[error("OpenTelemetry error: {0}")]
+    OtelError(#[from] opentelemetry_otlp::ExporterBuildError),
+}
+
+// Reasons a depth update can't be applied to a book; any of these leaves the
+// book needing a resync, unlike network errors which only need a reconnect
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+enum OrderBookError {
+    #[error("update u={final_update_id} is older than lastUpdateId={last_update_id}")]
+    StaleUpdate { final_update_id: u64, last_update_id: u64 },
+    #[error("update U={first_update_id} u={final_update_id} does not bridge snapshot lastUpdateId={last_update_id}")]
+    SnapshotGap { first_update_id: u64, final_update_id: u64, last_update_id: u64 },
+    #[error("update pu={prev_final_update_id} does not follow lastUpdateId={last_update_id}")]
+    SequenceGap { prev_final_update_id: u64, last_update_id: u64 },
+    #[error("update u={final_update_id} is far behind lastUpdateId={last_update_id}, sequence was reset")]
+    SequenceReset { final_update_id: u64, last_update_id: u64 },
+    #[error("update u={final_update_id} has an unparseable level [{price:?}, {qty:?}]")]
+    InvalidLevel { final_update_id: u64, price: String, qty: String },
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for WebSocketError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        use tokio_tungstenite::tungstenite::error::{CapacityError, Error};
+        match e {
+            Error::Capacity(CapacityError::MessageTooLong { size, max_size }) => {
+                WebSocketError::MessageTooLarge { size, max_size }
+            }
+            e => WebSocketError::WebSocketError(Box::new(e)),
+        }
+    }
+}
+
+// Binance futures market the client connects to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Market {
+    // USD-margined futures
+    #[default]
+    UsdM,
+    // Coin-margined futures
+    CoinM,
+}
+
+impl Market {
+    fn ws_url(self) -> &'static str {
+        match self {
+            Market::UsdM => BINANCE_WS_URL,
+            Market::CoinM => BINANCE_COIN_M_WS_URL,
+        }
+    }
+
+    fn api_url(self) -> &'static str {
+        match self {
+            Market::UsdM => BINANCE_API_URL,
+            Market::CoinM => BINANCE_COIN_M_API_URL,
+        }
+    }
+
+    fn depth_path(self) -> &'static str {
+        match self {
+            Market::UsdM => "/fapi/v1/depth",
+            Market::CoinM => "/dapi/v1/depth",
+        }
+    }
+
+    fn exchange_info_path(self) -> &'static str {
+        match self {
+            Market::UsdM => "/fapi/v1/exchangeInfo",
+            Market::CoinM => "/dapi/v1/exchangeInfo",
+        }
+    }
+}
+
+// Cache key of a symbol's (or book key's) market data, see MarketStore
+type MarketKey = (Market, String);
+
+// Handling of incoming frames while processing is paused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PausePolicy {
+    // Discard frames; depth books are refetched from a snapshot on resume
+    Drop,
+    // Hold frames (up to pause_buffer_capacity) and replay them on resume
+    Buffer,
+}
+
+// Reaction to frames of a stream type that isn't recognized, or isn't routed
+// into the cache (e.g. aggTrade). Every policy counts them per type; warn_once
+// and count also let streams of known but unhandled types be configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum UnknownStreamPolicy {
+    // Warn on every frame
+    Warn,
+    // Warn on the first frame of each type, then only count
+    WarnOnce,
+    // Only count, see unknown_stream_counts
+    Count,
+    // Fail the frame, which ends the connection; for catching unexpected streams
+    Error,
+}
+
+// Order book events broadcast after each accepted depth update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BookBroadcastMode {
+    // Top broadcast_depth levels per side after every update
+    Snapshot,
+    // The full book once on sync, then only the levels each update changed
+    Delta,
+}
+
+// File format of the trade archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TradeArchiveFormat {
+    // Appended to trades.ndjson, one trade payload per line
+    Ndjson,
+    // One complete trades-<ms>.parquet file per archive write (needs the `parquet` feature)
+    Parquet,
+}
+
+// Casing of the symbol part of subscribed stream names; Binance expects lowercase
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StreamNameCase {
+    Lower,
+    Upper,
+    // Symbols exactly as configured
+    Preserve,
+}
+
+impl StreamNameCase {
+    fn apply(self, symbol: &str) -> String {
+        match self {
+            StreamNameCase::Lower => symbol.to_lowercase(),
+            StreamNameCase::Upper => symbol.to_uppercase(),
+            StreamNameCase::Preserve => symbol.to_string(),
+        }
+    }
+}
+
+// Where quote and top_of_book read the best bid/ask from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum QuoteSource {
+    // The depth book only
+    Book,
+    // The depth book while it is synced and fresh, else the bookTicker
+    PreferBook,
+    // The bookTicker only
+    BookTicker,
+}
+
+// Where the prevailing best bid/ask attached to each trade is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TradeQuoteSource {
+    // The symbol's depth book, while synced
+    Book,
+    // The latest bookTicker
+    BookTicker,
+}
+
+// Response when the bookTicker and the depth book disagree beyond cross_check_tolerance_bps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DivergenceAction {
+    // Warn and record the divergence on the book
+    Log,
+    // Also mark the book unsynced for a sync_deadline retry to refresh (requires sync_deadline_ms)
+    Resync,
+    // Also mark the book unsynced and fetch a fresh snapshot right away, at most
+    // once per DIVERGENCE_REFETCH_COOLDOWN_SECS per book (logged only in between)
+    Snapshot,
+}
+
+// Pre-flight validation of configured symbols against exchangeInfo
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SymbolValidation {
+    Off,
+    Warn,
+    Error,
+}
+
+// Binance futures stream types, parsed from the part of a stream name after
+// the symbol (e.g. "depth@100ms", "kline_1m", "markPrice@1s")
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum StreamType {
+    BookTicker,
+    Trade,
+    AggTrade,
+    // Diff depth, the stream the order books are maintained from
+    Depth,
+    // Partial book depth (depth5/depth10/depth20)
+    PartialDepth(u8),
+    Kline,
+    MarkPrice,
+    ForceOrder,
+    Ticker,
+    MiniTicker,
+}
+
+impl StreamType {
+    // Stream types handle_message routes into the cache
+    fn is_handled(self) -> bool {
+        matches!(self, StreamType::BookTicker | StreamType::Trade | StreamType::Depth | StreamType::MarkPrice)
+    }
+}
+
+impl std::str::FromStr for StreamType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Strip update-speed qualifiers such as "@100ms" or "@1s"
+        let name = s.split('@').next().unwrap_or_default();
+        let stream_type = match name {
+            "bookTicker" => StreamType::BookTicker,
+            "trade" => StreamType::Trade,
+            "aggTrade" => StreamType::AggTrade,
+            "depth" => StreamType::Depth,
+            "depth5" => StreamType::PartialDepth(5),
+            "depth10" => StreamType::PartialDepth(10),
+            "depth20" => StreamType::PartialDepth(20),
+            "markPrice" => StreamType::MarkPrice,
+            "forceOrder" => StreamType::ForceOrder,
+            "ticker" => StreamType::Ticker,
+            "miniTicker" => StreamType::MiniTicker,
+            kline if kline.starts_with("kline_") => StreamType::Kline,
+            _ => return Err(format!("unknown stream type {:?}", s)),
+        };
+        Ok(stream_type)
+    }
+}
+
+const DEFAULT_STREAMS: &[&str] = &["bookTicker", "trade", "depth"];
+
+// Client configuration; defaults mirror the constants above
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ClientConfig {
+    // Label attached to every log line, to tell apart several running instances
+    instance_id: Option<String>,
+    symbols: Vec<String>,
+    market: Market,
+    // Overrides for the market's default endpoints
+    ws_url: Option<String>,
+    api_url: Option<String>,
+    // PEM file of pinned certificates or CAs. When set, only these are trusted for
+    // the WebSocket and REST connections; the system trust store is ignored.
+    tls_ca_file: Option<std::path::PathBuf>,
+    // Replace the path of ws_url for combined streams (default: ws_url's own, /stream)
+    // and for raw_stream (default: the combined path with /stream swapped for /ws)
+    combined_path: Option<String>,
+    raw_path: Option<String>,
+    stream_name_case: StreamNameCase,
+    // Stream types subscribed for every symbol
+    streams: Vec<String>,
+    // Narrow the bookTicker, trade and depth streams to a subset of `symbols`;
+    // None subscribes every symbol. Only depth symbols get REST snapshots.
+    book_ticker_symbols: Option<Vec<String>>,
+    trade_symbols: Option<Vec<String>>,
+    depth_symbols: Option<Vec<String>>,
+    // All-market streams subscribed once, e.g. "!bookTicker" or "!markPrice@arr"
+    firehose_streams: Vec<String>,
+    // Symbols cached from the firehose streams; empty caches every symbol
+    firehose_symbols: Vec<String>,
+    // Connect to the raw /ws/ endpoint, which serves one stream without the combined
+    // {"stream", "data"} wrapper; needs exactly one symbol and one stream
+    raw_stream: bool,
+    batch_size: usize,
+    #[serde(rename = "batch_interval_ms", deserialize_with = "duration_ms::deserialize")]
+    batch_interval: Duration,
+    // Number of operational events retained in the event log
+    event_log_capacity: usize,
+    // Grace period after startup during which failures are not counted
+    #[serde(rename = "warmup_period_ms", deserialize_with = "duration_ms::deserialize")]
+    warmup_period: Duration,
+    // A connection that stays up this long resets the reconnect counter
+    #[serde(rename = "stable_connection_period_ms", deserialize_with = "duration_ms::deserialize")]
+    stable_connection_period: Duration,
+    // Max allowed gap (bps) between the bookTicker and depth book best prices; None disables the check
+    cross_check_tolerance_bps: Option<Decimal>,
+    // What to do when the cross-check finds the book diverging
+    divergence_action: DivergenceAction,
+    // How often to run the order book compaction pass; None disables it
+    #[serde(rename = "compaction_interval_ms", deserialize_with = "duration_ms::deserialize_option")]
+    compaction_interval: Option<Duration>,
+    // Levels further than this from the mid (bps) are trimmed during compaction
+    compaction_max_distance_bps: Decimal,
+    // How often every book is checked for corruption (zero levels, crossed while
+    // synced, update id going backwards); None disables it
+    #[serde(rename = "consistency_check_interval_ms", deserialize_with = "duration_ms::deserialize_option")]
+    consistency_check_interval: Option<Duration>,
+    // A synced book with no bids or no asks for this long is refreshed from a snapshot;
+    // None leaves one-sided books alone
+    #[serde(rename = "empty_side_timeout_ms", deserialize_with = "duration_ms::deserialize_option")]
+    empty_side_timeout: Option<Duration>,
+    // Desyncs of a symbol's diff depth book within depth_downgrade_window after
+    // which it is kept from the depth20 partial stream instead, for the rest of
+    // the run; None never downgrades
+    depth_downgrade_after: Option<u32>,
+    #[serde(rename = "depth_downgrade_window_ms", deserialize_with = "duration_ms::deserialize")]
+    depth_downgrade_window: Duration,
+    // Offsets from each symbol's next funding time (negative is before, e.g.
+    // [-5000, 5000]) at which its depth books are proactively refreshed from a
+    // snapshot, since books around funding are volatile; needs the markPrice
+    // stream for funding times. Empty disables it.
+    funding_refresh_offsets_ms: Vec<i64>,
+    // How often each book's top-N notional is sampled for is_thin; None disables sampling
+    #[serde(rename = "thinness_sample_interval_ms", deserialize_with = "duration_ms::deserialize_option")]
+    thinness_sample_interval: Option<Duration>,
+    // Levels per side in the sampled notional
+    thinness_levels: usize,
+    // Samples in the rolling baseline
+    thinness_window: usize,
+    // Best-price changes per second above which a book is reported as flickering; None disables it
+    flicker_alert_rate: Option<f64>,
+    // Fraction of a book's depth updates accepted per ingress sample below which
+    // it is reported; None disables the alert
+    acceptance_alert_rate: Option<f64>,
+    // How often each synced book's spread is sampled for time_weighted_spread; None disables it
+    #[serde(rename = "spread_sample_interval_ms", deserialize_with = "duration_ms::deserialize_option")]
+    spread_sample_interval: Option<Duration>,
+    // How long spread samples are kept, bounding the window time_weighted_spread can cover
+    #[serde(rename = "spread_history_ms", deserialize_with = "duration_ms::deserialize")]
+    spread_history: Duration,
+    // Window order_flow_imbalance sums each book's per-update OFI over; None
+    // keeps no OFI history
+    #[serde(rename = "ofi_window_ms", deserialize_with = "duration_ms::deserialize_option")]
+    ofi_window: Option<Duration>,
+    // Where order book checkpoints are written (bincode for .bin, else JSON); None disables checkpointing
+    checkpoint_path: Option<std::path::PathBuf>,
+    #[serde(rename = "checkpoint_interval_ms", deserialize_with = "duration_ms::deserialize")]
+    checkpoint_interval: Duration,
+    // Books restored from a checkpoint at most this old skip their startup snapshot
+    // and resume from the stream if it continues their sequence; older ones are
+    // fetched as usual
+    #[serde(rename = "checkpoint_max_age_ms", deserialize_with = "duration_ms::deserialize")]
+    checkpoint_max_age: Duration,
+    // Capacity of the market event broadcast channel
+    broadcast_capacity: usize,
+    // Levels per side included in broadcast order book events
+    broadcast_depth: usize,
+    // Whether subscribers get top-N snapshots or a delta feed of book changes
+    book_broadcast: BookBroadcastMode,
+    // A book still unsynced this long after its snapshot gets a fresh one; None disables retries
+    #[serde(rename = "sync_deadline_ms", deserialize_with = "duration_ms::deserialize_option")]
+    sync_deadline: Option<Duration>,
+    // Snapshot retries per book before giving up until it syncs
+    max_sync_retries: u32,
+    // Number of initial snapshot fetches in flight at once
+    snapshot_concurrency: usize,
+    // Whether an unparseable REST snapshot aborts initialization (true) or skips the symbol
+    fatal_snapshot_parse_errors: bool,
+    // Fetch exchangeInfo (tick/step sizes, symbol status) at startup; off by
+    // default since it adds a REST call before connecting
+    fetch_exchange_info: bool,
+    // Warn when a depth update price isn't a multiple of the symbol's exchangeInfo tick size
+    validate_tick_size: bool,
+    // Trades retained per symbol for recent_trades and realized_volatility; 0 keeps only the last
+    trade_buffer_size: usize,
+    // Source of the best bid/ask served by quote and top_of_book
+    quote_source: QuoteSource,
+    // Also keep trades annotated with the best bid/ask from this source when they were
+    // processed (trade_buffer_size of them, at least one); None disables it
+    trade_quote_source: Option<TradeQuoteSource>,
+    // Directory every received trade is persisted to, so the in-memory buffer can stay small;
+    // None disables it. Trades are queued until written, so none fall between buffer and file.
+    trade_archive_dir: Option<std::path::PathBuf>,
+    trade_archive_format: TradeArchiveFormat,
+    #[serde(rename = "trade_archive_interval_ms", deserialize_with = "duration_ms::deserialize")]
+    trade_archive_interval: Duration,
+    // Record per-stream message inter-arrival statistics
+    record_inter_arrival: bool,
+    // Stamp cached trades and book tickers with the local time their frame was
+    // read (received_at_ms), next to the exchange event time
+    stamp_receive_time: bool,
+    // Drop trades whose id is not above the symbol's last stored trade, such as
+    // ones delivered again across a reconnect
+    dedup_trades: bool,
+    // Hold back per-book startup logs (snapshot installs, books waiting for sync,
+    // pre-sync rejections) until each book first syncs, then log one line for it.
+    // Books still unsynced after sync_deadline (else warmup_period) get one
+    // warning each, and their logs are no longer held back.
+    quiet_start: bool,
+    // Symbols whose every message is logged from startup; adjustable at runtime
+    debug_symbols: Vec<String>,
+    // What to do with incoming frames while processing is paused
+    pause_policy: PausePolicy,
+    // Max frames held while paused under PausePolicy::Buffer
+    pause_buffer_capacity: usize,
+    // Reaction to frames of unrecognized or unrouted stream types
+    unknown_stream_policy: UnknownStreamPolicy,
+    // Re-send each cached bookTicker as a StaleBookTicker event after a reconnect
+    replay_book_tickers_on_reconnect: bool,
+    // Tasks that parse frames and update the cache, sharded by symbol; 0 handles
+    // frames on the read loop itself
+    message_workers: usize,
+    // Lock shards of the per-symbol cache maps (books, tickers, trades, mark
+    // prices); must be a power of two above 1. More shards cut contention
+    // between workers and readers hitting different symbols, at the cost of a
+    // little memory per map and slower whole-map iteration (stats, sampling).
+    // None uses the dashmap default of four per CPU, rounded to a power of two.
+    cache_shards: Option<usize>,
+    // Largest message (and frame) read from the WebSocket, in bytes. A bigger one is
+    // refused before it is buffered and ends the session, which then reconnects.
+    max_message_size: usize,
+    // How to treat configured symbols missing from exchangeInfo; skipped without fetch_exchange_info
+    symbol_validation: SymbolValidation,
+    // Close codes that warrant close_code_backoff instead of the normal reconnect delay
+    long_backoff_close_codes: Vec<u16>,
+    #[serde(rename = "close_code_backoff_ms", deserialize_with = "duration_ms::deserialize")]
+    close_code_backoff: Duration,
+    // REST backoff after a 429 or a 418 IP ban, used when the response has no Retry-After
+    #[serde(rename = "rate_limit_backoff_ms", deserialize_with = "duration_ms::deserialize")]
+    rate_limit_backoff: Duration,
+    #[serde(rename = "ip_ban_backoff_ms", deserialize_with = "duration_ms::deserialize")]
+    ip_ban_backoff: Duration,
+    // Directory for Parquet trade and book ticker files; None disables it (needs the `parquet` feature)
+    parquet_dir: Option<std::path::PathBuf>,
+    // A Parquet file is rotated once it is this old or holds parquet_max_rows rows
+    #[serde(rename = "parquet_rotate_interval_ms", deserialize_with = "duration_ms::deserialize")]
+    parquet_rotate_interval: Duration,
+    parquet_max_rows: usize,
+    // Address each book and bookTicker top-of-book change is sent to as a UDP
+    // datagram (layout in udp_sink.rs), e.g. "127.0.0.1:9000"; None disables it
+    udp_top_of_book_addr: Option<std::net::SocketAddr>,
+    // Address of the gRPC server over the cache (service in proto/market_data.proto),
+    // e.g. "127.0.0.1:50051"; None disables it (needs the `grpc` feature)
+    grpc_addr: Option<std::net::SocketAddr>,
+    // Data not updated within this long is treated as stale and hidden from reads; None disables it
+    #[serde(rename = "stale_after_ms", deserialize_with = "duration_ms::deserialize_option")]
+    stale_after: Option<Duration>,
+    // Per-symbol quantity below which a level is treated as removed
+    min_level_qty: std::collections::HashMap<String, Decimal>,
+    // Threshold for other symbols, in multiples of their exchangeInfo step size; None keeps exact zero
+    min_level_qty_steps: Option<Decimal>,
+    // Per-symbol bucket width of a grouped book view maintained alongside the full book
+    group_tick: std::collections::HashMap<String, Decimal>,
+    // Levels per side dumped at warn level when a synced book rejects an update; None disables it
+    desync_dump_levels: Option<usize>,
+    // An update this far behind the book's lastUpdateId means the exchange restarted its
+    // sequence; the book is refreshed from a snapshot instead of rejecting it forever.
+    // None treats it as an ordinary stale update.
+    sequence_reset_threshold: Option<u64>,
+    // Levels requested per REST snapshot, and per-symbol overrides of it
+    snapshot_depth: usize,
+    symbol_snapshot_depth: std::collections::HashMap<String, usize>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            instance_id: None,
+            symbols: Vec::new(),
+            market: Market::default(),
+            ws_url: None,
+            api_url: None,
+            tls_ca_file: None,
+            combined_path: None,
+            raw_path: None,
+            stream_name_case: StreamNameCase::Lower,
+            streams: DEFAULT_STREAMS.iter().map(|s| s.to_string()).collect(),
+            book_ticker_symbols: None,
+            trade_symbols: None,
+            depth_symbols: None,
+            firehose_streams: Vec::new(),
+            firehose_symbols: Vec::new(),
+            raw_stream: false,
+            batch_size: BATCH_SIZE,
+            batch_interval: Duration::from_millis(BATCH_INTERVAL_MS),
+            event_log_capacity: EVENT_LOG_CAPACITY,
+            warmup_period: Duration::from_secs(WARMUP_PERIOD_SECS),
+            stable_connection_period: Duration::from_secs(STABLE_CONNECTION_SECS),
+            cross_check_tolerance_bps: None,
+            divergence_action: DivergenceAction::Log,
+            compaction_interval: None,
+            consistency_check_interval: None,
+            empty_side_timeout: None,
+            depth_downgrade_after: None,
+            depth_downgrade_window: Duration::from_secs(DEPTH_DOWNGRADE_WINDOW_SECS),
+            funding_refresh_offsets_ms: Vec::new(),
+            compaction_max_distance_bps: Decimal::from(COMPACTION_MAX_DISTANCE_BPS),
+            thinness_sample_interval: None,
+            thinness_levels: THINNESS_LEVELS,
+            thinness_window: THINNESS_WINDOW,
+            flicker_alert_rate: None,
+            acceptance_alert_rate: None,
+            spread_sample_interval: None,
+            spread_history: Duration::from_secs(SPREAD_HISTORY_SECS),
+            ofi_window: None,
+            checkpoint_path: None,
+            checkpoint_interval: Duration::from_secs(CHECKPOINT_INTERVAL_SECS),
+            checkpoint_max_age: Duration::from_secs(CHECKPOINT_MAX_AGE_SECS),
+            broadcast_capacity: BROADCAST_CAPACITY,
+            broadcast_depth: BROADCAST_DEPTH,
+            book_broadcast: BookBroadcastMode::Snapshot,
+            sync_deadline: None,
+            max_sync_retries: MAX_SYNC_RETRIES,
+            snapshot_concurrency: 1,
+            fatal_snapshot_parse_errors: true,
+            fetch_exchange_info: false,
+            validate_tick_size: false,
+            trade_buffer_size: 0,
+            trade_quote_source: None,
+            quote_source: QuoteSource::Book,
+            trade_archive_dir: None,
+            trade_archive_format: TradeArchiveFormat::Ndjson,
+            trade_archive_interval: Duration::from_secs(TRADE_ARCHIVE_INTERVAL_SECS),
+            record_inter_arrival: false,
+            stamp_receive_time: false,
+            dedup_trades: false,
+            quiet_start: false,
+            debug_symbols: Vec::new(),
+            pause_policy: PausePolicy::Buffer,
+            pause_buffer_capacity: PAUSE_BUFFER_CAPACITY,
+            unknown_stream_policy: UnknownStreamPolicy::Warn,
+            replay_book_tickers_on_reconnect: false,
+            message_workers: 0,
+            cache_shards: None,
+            max_message_size: MAX_MESSAGE_SIZE,
+            symbol_validation: SymbolValidation::Warn,
+            // 1008 policy violation, 1013 try again later
+            long_backoff_close_codes: vec![1008, 1013],
+            close_code_backoff: Duration::from_secs(CLOSE_CODE_BACKOFF_SECS),
+            rate_limit_backoff: Duration::from_secs(RATE_LIMIT_BACKOFF_SECS),
+            ip_ban_backoff: Duration::from_secs(IP_BAN_BACKOFF_SECS),
+            parquet_dir: None,
+            parquet_rotate_interval: Duration::from_secs(PARQUET_ROTATE_INTERVAL_SECS),
+            parquet_max_rows: PARQUET_MAX_ROWS,
+            udp_top_of_book_addr: None,
+            grpc_addr: None,
+            stale_after: None,
+            desync_dump_levels: None,
+            min_level_qty: std::collections::HashMap::new(),
+            min_level_qty_steps: None,
+            group_tick: std::collections::HashMap::new(),
+            sequence_reset_threshold: Some(SEQUENCE_RESET_THRESHOLD),
+            snapshot_depth: ORDER_BOOK_DEPTH,
+            symbol_snapshot_depth: std::collections::HashMap::new(),
+        }
+    }
+}
+
+impl ClientConfig {
+    // Load a TOML or JSON config file, chosen by extension
+    fn from_file(path: impl AsRef<std::path::Path>) -> Result<Self, WebSocketError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        let config: ClientConfig = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&contents)
+                .map_err(|e| WebSocketError::ConfigError(format!("{}: {}", path.display(), e)))?,
+            Some("json") => serde_json::from_str(&contents)?,
+            _ => {
+                return Err(WebSocketError::ConfigError(format!(
+                    "{}: unsupported config format, expected .toml or .json",
+                    path.display()
+                )))
+            }
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), WebSocketError> {
+        let invalid = |msg: String| Err(WebSocketError::ConfigError(msg));
+
+        if self.instance_id.as_deref().is_some_and(str::is_empty) {
+            return invalid("instance_id must not be empty".into());
+        }
+        if self.symbols.is_empty() {
+            return invalid("symbols must not be empty".into());
+        }
+        if let Some(symbol) = self.symbols.iter().find(|s| s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric())) {
+            return invalid(format!("invalid symbol {:?}", symbol));
+        }
+        if self.streams.is_empty() {
+            return invalid("streams must not be empty".into());
+        }
+        for (i, stream) in self.streams.iter().enumerate() {
+            if self.streams[..i].contains(stream) {
+                return invalid(format!("duplicate stream {:?}", stream));
+            }
+            match stream.parse::<StreamType>() {
+                Ok(stream_type) if stream_type.is_handled() => {}
+                // Subscribing ahead of support is fine once its frames are only counted
+                Ok(_) if matches!(self.unknown_stream_policy, UnknownStreamPolicy::WarnOnce | UnknownStreamPolicy::Count) => {}
+                Ok(stream_type) => return invalid(format!("stream {:?} ({:?}) is not handled by the client", stream, stream_type)),
+                Err(e) => return invalid(e),
+            }
+        }
+        for (name, subset) in [
+            ("book_ticker_symbols", &self.book_ticker_symbols),
+            ("trade_symbols", &self.trade_symbols),
+            ("depth_symbols", &self.depth_symbols),
+        ] {
+            let unknown = subset.iter().flatten().find(|s| !self.symbols.iter().any(|c| c.eq_ignore_ascii_case(s)));
+            if let Some(symbol) = unknown {
+                return invalid(format!("{} entry {:?} is not in symbols", name, symbol));
+            }
+        }
+        for stream in &self.firehose_streams {
+            let Some(name) = stream.strip_prefix('!') else {
+                return invalid(format!("firehose stream {:?} must start with '!'", stream));
+            };
+            match name.parse::<StreamType>() {
+                Ok(StreamType::BookTicker | StreamType::MarkPrice) => {}
+                Ok(stream_type) => return invalid(format!("firehose stream {:?} ({:?}) is not handled by the client", stream, stream_type)),
+                Err(e) => return invalid(e),
+            }
+        }
+        if self.raw_stream {
+            if self.symbols.len() != 1 || self.streams.len() != 1 {
+                return invalid("raw_stream needs exactly one symbol and one stream".into());
+            }
+            if !self.firehose_streams.is_empty() {
+                return invalid("raw_stream cannot be combined with firehose_streams".into());
+            }
+            let combined_path = self.combined_path.as_deref().unwrap_or(self.ws_url());
+            if self.raw_path.is_none() && !combined_path.ends_with("/stream") {
+                return invalid(format!("raw_stream needs raw_path or a combined endpoint ending in /stream, got {:?}", combined_path));
+            }
+        }
+        match self.trade_quote_source {
+            Some(TradeQuoteSource::Book) if self.depth_streams().next().is_none() => {
+                return invalid("trade_quote_source = \"book\" needs a depth stream".into());
+            }
+            Some(TradeQuoteSource::BookTicker) if !self.has_book_ticker_stream() => {
+                return invalid("trade_quote_source = \"book_ticker\" needs the bookTicker stream".into());
+            }
+            _ => {}
+        }
+        if !self.funding_refresh_offsets_ms.is_empty() {
+            if self.depth_streams().next().is_none() {
+                return invalid("funding_refresh_offsets_ms needs a depth stream".into());
+            }
+            if !self.has_mark_price_stream() {
+                return invalid("funding_refresh_offsets_ms needs the markPrice stream".into());
+            }
+        }
+        if self.quote_source != QuoteSource::Book && !self.has_book_ticker_stream() {
+            return invalid(format!("quote_source = {:?} needs the bookTicker stream", self.quote_source));
+        }
+        if self.validate_tick_size && !self.fetch_exchange_info {
+            return invalid("validate_tick_size needs fetch_exchange_info".into());
+        }
+        if self.symbol_validation == SymbolValidation::Error && !self.fetch_exchange_info {
+            return invalid("symbol_validation = \"error\" needs fetch_exchange_info".into());
+        }
+        if self.cache_shards.is_some_and(|shards| shards < 2 || !shards.is_power_of_two()) {
+            return invalid("cache_shards must be a power of two greater than 1".into());
+        }
+        if self.max_message_size == 0 {
+            return invalid("max_message_size must be greater than zero".into());
+        }
+        if self.snapshot_concurrency == 0 {
+            return invalid("snapshot_concurrency must be greater than zero".into());
+        }
+        if self.batch_size == 0 {
+            return invalid("batch_size must be greater than zero".into());
+        }
+        if self.batch_interval.is_zero() {
+            return invalid("batch_interval_ms must be greater than zero".into());
+        }
+        if matches!(self.stale_after, Some(d) if d.is_zero()) {
+            return invalid("stale_after_ms must be greater than zero".into());
+        }
+        if matches!(self.empty_side_timeout, Some(d) if d.is_zero()) {
+            return invalid("empty_side_timeout_ms must be greater than zero".into());
+        }
+        if self.depth_downgrade_after.is_some() {
+            if self.depth_downgrade_after == Some(0) || self.depth_downgrade_window.is_zero() {
+                return invalid("depth_downgrade_after and depth_downgrade_window_ms must be greater than zero".into());
+            }
+            if self.raw_stream {
+                return invalid("depth_downgrade_after needs the combined stream endpoint".into());
+            }
+        }
+        if matches!(self.sync_deadline, Some(d) if d.is_zero()) {
+            return invalid("sync_deadline_ms must be greater than zero".into());
+        }
+        // Nothing else refetches a book the resync action marked unsynced
+        if self.divergence_action == DivergenceAction::Resync && self.sync_deadline.is_none() {
+            return invalid("divergence_action = \"resync\" needs sync_deadline_ms".into());
+        }
+        if matches!(self.compaction_interval, Some(d) if d.is_zero()) {
+            return invalid("compaction_interval_ms must be greater than zero".into());
+        }
+        if matches!(self.thinness_sample_interval, Some(d) if d.is_zero()) {
+            return invalid("thinness_sample_interval_ms must be greater than zero".into());
+        }
+        if self.thinness_sample_interval.is_some() && (self.thinness_levels == 0 || self.thinness_window == 0) {
+            return invalid("thinness_levels and thinness_window must be greater than zero".into());
+        }
+        if self.flicker_alert_rate.is_some_and(|rate| rate.is_nan() || rate <= 0.0) {
+            return invalid("flicker_alert_rate must be greater than zero".into());
+        }
+        if self.acceptance_alert_rate.is_some_and(|rate| !(0.0..=1.0).contains(&rate)) {
+            return invalid("acceptance_alert_rate must be between 0 and 1".into());
+        }
+        if matches!(self.spread_sample_interval, Some(d) if d.is_zero()) {
+            return invalid("spread_sample_interval_ms must be greater than zero".into());
+        }
+        if matches!(self.ofi_window, Some(d) if d.is_zero()) {
+            return invalid("ofi_window_ms must be greater than zero".into());
+        }
+        if self.spread_sample_interval.is_some_and(|interval| self.spread_history < interval) {
+            return invalid("spread_history_ms must be at least spread_sample_interval_ms".into());
+        }
+        if self.checkpoint_path.is_some() && self.checkpoint_interval.is_zero() {
+            return invalid("checkpoint_interval_ms must be greater than zero".into());
+        }
+        if self.pause_policy == PausePolicy::Buffer && self.pause_buffer_capacity == 0 {
+            return invalid("pause_buffer_capacity must be greater than zero with the buffer pause policy".into());
+        }
+        if self.trade_archive_dir.is_some() {
+            if self.trade_archive_format == TradeArchiveFormat::Parquet && !cfg!(feature = "parquet") {
+                return invalid("trade_archive_format = \"parquet\" requires building with the `parquet` feature".into());
+            }
+            if self.trade_archive_interval.is_zero() {
+                return invalid("trade_archive_interval_ms must be greater than zero".into());
+            }
+        }
+        if self.grpc_addr.is_some() && !cfg!(feature = "grpc") {
+            return invalid("grpc_addr requires building with the `grpc` feature".into());
+        }
+        if self.parquet_dir.is_some() {
+            if !cfg!(feature = "parquet") {
+                return invalid("parquet_dir requires building with the `parquet` feature".into());
+            }
+            if self.parquet_rotate_interval.is_zero() {
+                return invalid("parquet_rotate_interval_ms must be greater than zero".into());
+            }
+            if self.parquet_max_rows == 0 {
+                return invalid("parquet_max_rows must be greater than zero".into());
+            }
+        }
+        if self.broadcast_capacity == 0 {
+            return invalid("broadcast_capacity must be greater than zero".into());
+        }
+        if self.compaction_max_distance_bps <= Decimal::ZERO {
+            return invalid("compaction_max_distance_bps must be positive".into());
+        }
+        if let Some((symbol, _)) = self.min_level_qty.iter().find(|(_, qty)| **qty < Decimal::ZERO) {
+            return invalid(format!("min_level_qty for {} must not be negative", symbol));
+        }
+        if matches!(self.min_level_qty_steps, Some(steps) if steps < Decimal::ZERO) {
+            return invalid("min_level_qty_steps must not be negative".into());
+        }
+        if let Some((symbol, _)) = self.group_tick.iter().find(|(_, tick)| **tick <= Decimal::ZERO) {
+            return invalid(format!("group_tick for {} must be positive", symbol));
+        }
+        if !SNAPSHOT_DEPTH_LIMITS.contains(&self.snapshot_depth) {
+            return invalid(format!("snapshot_depth must be one of {:?}", SNAPSHOT_DEPTH_LIMITS));
+        }
+        if let Some((symbol, _)) = self.symbol_snapshot_depth.iter().find(|(_, depth)| !SNAPSHOT_DEPTH_LIMITS.contains(depth)) {
+            return invalid(format!("symbol_snapshot_depth for {} must be one of {:?}", symbol, SNAPSHOT_DEPTH_LIMITS));
+        }
+        if matches!(self.cross_check_tolerance_bps, Some(t) if t < Decimal::ZERO) {
+            return invalid("cross_check_tolerance_bps must not be negative".into());
+        }
+        for url in [&self.ws_url, &self.api_url].into_iter().flatten() {
+            if let Err(e) = Url::parse(url) {
+                return invalid(format!("invalid URL {:?}: {}", url, e));
+            }
+        }
+        Ok(())
+    }
+
+    // Configured diff depth streams, e.g. "depth" or "depth@500ms"
+    fn depth_streams(&self) -> impl Iterator<Item = &str> {
+        self.streams
+            .iter()
+            .map(String::as_str)
+            .filter(|s| s.parse::<StreamType>() == Ok(StreamType::Depth))
+    }
+
+    fn ws_url(&self) -> &str {
+        self.ws_url.as_deref().unwrap_or(self.market.ws_url())
+    }
+
+    // Whether a symbol gets the streams of this type
+    fn subscribes(&self, symbol: &str, stream_type: StreamType) -> bool {
+        let subset = match stream_type {
+            StreamType::BookTicker => &self.book_ticker_symbols,
+            StreamType::Trade => &self.trade_symbols,
+            StreamType::Depth => &self.depth_symbols,
+            _ => return true,
+        };
+        subset.as_ref().is_none_or(|symbols| symbols.iter().any(|s| s.eq_ignore_ascii_case(symbol)))
+    }
+
+    // Snapshot depth for a symbol: its override if configured, else snapshot_depth
+    fn snapshot_depth_for(&self, symbol: &str) -> usize {
+        self.symbol_snapshot_depth
+            .iter()
+            .find(|(s, _)| s.eq_ignore_ascii_case(symbol))
+            .map_or(self.snapshot_depth, |(_, depth)| *depth)
+    }
+
+    fn has_book_ticker_stream(&self) -> bool {
+        self.streams.iter().any(|s| s.parse() == Ok(StreamType::BookTicker))
+            || self.firehose_streams.iter().any(|s| s == "!bookTicker")
+    }
+
+    fn has_mark_price_stream(&self) -> bool {
+        self.streams.iter().any(|s| s.parse() == Ok(StreamType::MarkPrice))
+            || self.firehose_streams.iter().any(|s| s.starts_with("!markPrice"))
+    }
+
+    // The single stream served unwrapped when raw_stream is set, e.g. "btcusdt@depth@100ms"
+    fn raw_stream_name(&self) -> Option<String> {
+        if !self.raw_stream {
+            return None;
+        }
+        let (symbol, stream) = (self.symbols.first()?, self.streams.first()?);
+        Some(format!("{}@{}", self.stream_name_case.apply(symbol), stream))
+    }
+
+    // Combined stream endpoint, without the ?streams= query
+    fn combined_endpoint(&self) -> Result<Url, url::ParseError> {
+        let mut url = Url::parse(self.ws_url())?;
+        if let Some(path) = &self.combined_path {
+            url.set_path(path);
+        }
+        Ok(url)
+    }
+
+    // Endpoint that raw stream names are appended to
+    fn raw_endpoint(&self) -> Result<Url, url::ParseError> {
+        let mut url = self.combined_endpoint()?;
+        let path = match &self.raw_path {
+            Some(path) => path.clone(),
+            None => format!("{}/ws", url.path().trim_end_matches("/stream")),
+        };
+        url.set_path(&path);
+        Ok(url)
+    }
+
+    fn api_url(&self) -> &str {
+        self.api_url.as_deref().unwrap_or(self.market.api_url())
+    }
+}
+
+// Serde helpers for durations expressed as integer milliseconds in config files
+mod duration_ms {
+    use serde::{Deserialize, Deserializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+
+    pub fn deserialize_option<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_millis))
+    }
+}
+
+// Time sources: all elapsed-time logic (batching, backoff, staleness, resync
+// timing) goes through tokio::time::Instant, sleep and interval rather than
+// std::time::Instant, so it all follows the runtime's one clock. SystemTime is
+// only used to stamp records with wall-clock time, via unix_time_ms.
+fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Serde for order book ladders: decimal strings in human-readable formats, so
+// JSON checkpoints stay inspectable, and Decimal's 16-byte encoding in binary
+// ones (rust_decimal's own Deserialize needs deserialize_any, which bincode lacks)
+mod decimal_levels {
+    use rust_decimal::Decimal;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    #[derive(PartialEq, Eq, PartialOrd, Ord)]
+    struct Level(Decimal);
+
+    impl Serialize for Level {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            if serializer.is_human_readable() {
+                Serialize::serialize(&self.0, serializer)
+            } else {
+                self.0.serialize().serialize(serializer)
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Level {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            if deserializer.is_human_readable() {
+                <Decimal as Deserialize>::deserialize(deserializer).map(Level)
+            } else {
+                <[u8; 16]>::deserialize(deserializer).map(|bytes| Level(Decimal::deserialize(bytes)))
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(levels: &BTreeMap<Decimal, Decimal>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(levels.iter().map(|(price, qty)| (Level(*price), Level(*qty))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<Decimal, Decimal>, D::Error> {
+        let levels = BTreeMap::<Level, Level>::deserialize(deserializer)?;
+        Ok(levels.into_iter().map(|(price, qty)| (price.0, qty.0)).collect())
+    }
+}
+
+// Kinds of operational events worth keeping for post-mortem debugging
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EventKind {
+    Connected,
+    Reconnect,
+    Closed,
+    Resync,
+    ParseError,
+    Divergence,
+    // Best price changing faster than flicker_alert_rate
+    Flicker,
+    // Depth update acceptance falling below acceptance_alert_rate
+    LowAcceptance,
+    // Symbol switched from the diff depth stream to partial depth
+    DepthDowngrade,
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+struct OperationalEvent {
+    at_ms: u64,
+    kind: EventKind,
+    symbol: Option<String>,
+    detail: String,
+}
+
+// Bounded ring buffer of the last N operational events
+struct EventLog {
+    capacity: usize,
+    events: Mutex<VecDeque<OperationalEvent>>,
+}
+
+impl EventLog {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn record(&self, kind: EventKind, symbol: Option<&str>, detail: impl Into<String>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let event = OperationalEvent {
+            at_ms: unix_time_ms(),
+            kind,
+            symbol: symbol.map(str::to_owned),
+            detail: detail.into(),
+        };
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        if events.len() >= self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    // Oldest first
+    fn snapshot(&self) -> Vec<OperationalEvent> {
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        events.iter().cloned().collect()
+    }
 }
 
 // Data structures for Binance WebSocket messages
@@ -53,6 +1124,21 @@ struct BookTickerData {
     ask_price: Decimal,
     #[serde(rename = "A", deserialize_with = "decimal_from_str")]
     ask_qty: Decimal,
+    // Local unix ms the frame was read, with stamp_receive_time; not part of the payload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    received_at_ms: Option<u64>,
+}
+
+impl BookTickerData {
+    fn top_of_book(&self) -> TopOfBook {
+        TopOfBook {
+            bid_price: self.bid_price,
+            bid_qty: self.bid_qty,
+            ask_price: self.ask_price,
+            ask_qty: self.ask_qty,
+            last_update_id: self.update_id,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -80,10 +1166,71 @@ struct TradeData {
     is_buyer_market_maker: bool,
     #[serde(rename = "M")]
     ignore: bool,
+    // Local unix ms the frame was read, with stamp_receive_time; not part of the payload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    received_at_ms: Option<u64>,
+}
+
+impl TradeData {
+    // The side that crossed the spread: a maker buyer means the seller took liquidity
+    fn aggressor_side(&self) -> Side {
+        if self.is_buyer_market_maker {
+            Side::Sell
+        } else {
+            Side::Buy
+        }
+    }
+}
+
+// A trade with the best bid/ask the cache held when it was processed
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+struct QuotedTrade {
+    trade: TradeData,
+    // None if the quote source had nothing for the symbol (e.g. book not synced)
+    bid_price: Option<Decimal>,
+    ask_price: Option<Decimal>,
+}
+
+impl QuotedTrade {
+    // Where the trade printed within the spread: 0 at the bid, 1 at the ask,
+    // outside [0, 1] beyond them. None without a quote or with a locked/crossed one.
+    #[allow(dead_code)]
+    fn spread_position(&self) -> Option<Decimal> {
+        let (bid, ask) = (self.bid_price?, self.ask_price?);
+        if ask <= bid {
+            return None;
+        }
+        Some((self.trade.price - bid) / (ask - bid))
+    }
+}
+
+// Mark price stream payload, with the auxiliary prices needed for funding and
+// basis calculations
+#[derive(Debug, Deserialize, Clone)]
+#[allow(dead_code)]
+struct MarkPriceData {
+    #[serde(rename = "E")]
+    event_time: u64,
+    #[serde(rename = "s")]
+    symbol: String,
+    #[serde(rename = "p", deserialize_with = "decimal_from_str")]
+    mark_price: Decimal,
+    #[serde(rename = "i", deserialize_with = "decimal_from_str")]
+    index_price: Decimal,
+    #[serde(rename = "P", deserialize_with = "decimal_from_str")]
+    estimated_settle_price: Decimal,
+    // Empty for contracts without funding (e.g. COIN-M delivery)
+    #[serde(rename = "r", deserialize_with = "optional_decimal_from_str")]
+    funding_rate: Option<Decimal>,
+    // 0 for contracts without funding
+    #[serde(rename = "T")]
+    next_funding_time: u64,
 }
 
 // New struct for depth update data
 #[derive(Debug, Deserialize, Clone)]
+#[allow(dead_code)]
 struct DepthUpdateData {
     #[serde(rename = "e")]
     event_type: String,
@@ -114,25 +1261,234 @@ struct OrderBookSnapshot {
     asks: Vec<[String; 2]>,
 }
 
-// New struct to hold order book state
+// Subset of the REST exchangeInfo response we care about
+#[derive(Debug, Deserialize)]
+struct ExchangeInfo {
+    symbols: Vec<ExchangeSymbol>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExchangeSymbol {
+    symbol: String,
+    // USD-M reports `status`, COIN-M reports `contractStatus`
+    #[serde(alias = "contractStatus")]
+    status: Option<String>,
+    filters: Vec<SymbolFilter>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "filterType")]
+enum SymbolFilter {
+    #[serde(rename = "PRICE_FILTER", rename_all = "camelCase")]
+    PriceFilter {
+        #[serde(deserialize_with = "decimal_from_str")]
+        tick_size: Decimal,
+    },
+    #[serde(rename = "LOT_SIZE", rename_all = "camelCase")]
+    LotSize {
+        #[serde(deserialize_with = "decimal_from_str")]
+        step_size: Decimal,
+    },
+    #[serde(other)]
+    Other,
+}
+
+// Cached trading rules for a symbol
 #[derive(Debug, Clone)]
-struct OrderBook {
+#[allow(dead_code)]
+struct SymbolInfo {
     symbol: String,
-    last_update_id: u64,
-    bids: BTreeMap<Decimal, Decimal>,
-    asks: BTreeMap<Decimal, Decimal>,
-    synced: bool,
+    status: Option<String>,
+    tick_size: Option<Decimal>,
+    step_size: Option<Decimal>,
 }
 
-impl OrderBook {
-    fn new(symbol: String) -> Self {
-        Self {
-            symbol,
-            last_update_id: 0,
-            bids: BTreeMap::new(),
-            asks: BTreeMap::new(),
-            synced: false,
+impl From<ExchangeSymbol> for SymbolInfo {
+    fn from(raw: ExchangeSymbol) -> Self {
+        let mut info = SymbolInfo {
+            symbol: raw.symbol.to_uppercase(),
+            status: raw.status,
+            tick_size: None,
+            step_size: None,
+        };
+        for filter in raw.filters {
+            match filter {
+                SymbolFilter::PriceFilter { tick_size } => info.tick_size = Some(tick_size),
+                SymbolFilter::LotSize { step_size } => info.step_size = Some(step_size),
+                SymbolFilter::Other => {}
+            }
         }
+        info
+    }
+}
+
+// Side of an order or trade, never of the book: buys consume asks, sells
+// consume bids. Reads of one book side take a BookSide instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+enum Side {
+    Buy,
+    Sell,
+}
+
+// One side of the book
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+enum BookSide {
+    Bid,
+    Ask,
+}
+
+// Both sides of the top of book, read from one consistent book state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+struct TopOfBook {
+    bid_price: Decimal,
+    bid_qty: Decimal,
+    ask_price: Decimal,
+    ask_qty: Decimal,
+    last_update_id: u64,
+}
+
+// Top levels of a symbol's book together with its last trade, for callers
+// that need one coherent view; see MarketDataCache::market_state
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+struct MarketState {
+    // Best first, at most the requested number of levels per side
+    bids: Vec<(Decimal, Decimal)>,
+    asks: Vec<(Decimal, Decimal)>,
+    // None if either side is empty
+    top: Option<TopOfBook>,
+    last_update_id: u64,
+    synced: bool,
+    // None if no trade has been seen or the trade stream is stale
+    last_trade: Option<TradeData>,
+}
+
+// Raw text frames received per second across all streams, over the last sample interval
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct IngressRate {
+    bytes_per_sec: f64,
+    messages_per_sec: f64,
+}
+
+// Top buckets of a book's grouped view, best first
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+struct GroupedBook {
+    tick: Decimal,
+    bids: Vec<(Decimal, Decimal)>,
+    asks: Vec<(Decimal, Decimal)>,
+}
+
+// Top of book together with the book's health, read under one guard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+struct Quote {
+    // For bookTicker quotes last_update_id is the ticker's update id
+    top: TopOfBook,
+    // Whether top came from the bookTicker rather than the depth book
+    from_book_ticker: bool,
+    // Always true for bookTicker quotes, which have nothing to sync
+    synced: bool,
+    // Time since the book last applied a snapshot or update; None if it never has.
+    // For bookTicker quotes only known when stale_after is set.
+    age: Option<Duration>,
+    // Past the configured stale_after (always false when that is unset)
+    stale: bool,
+}
+
+// New struct to hold order book state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OrderBook {
+    // Cache key: the symbol, or SYMBOL@STREAM for additional depth speeds
+    symbol: String,
+    last_update_id: u64,
+    #[serde(with = "decimal_levels")]
+    bids: BTreeMap<Decimal, Decimal>,
+    #[serde(with = "decimal_levels")]
+    asks: BTreeMap<Decimal, Decimal>,
+    synced: bool,
+    // Largest bookTicker vs depth best-price gap (bps) from the last cross-check, if beyond
+    // tolerance. Not checkpointed; the next cross-check recomputes it.
+    #[serde(skip)]
+    ticker_divergence_bps: Option<Decimal>,
+    // When the book last dropped out of sync; cleared once it resyncs
+    #[serde(skip)]
+    desynced_at: Option<tokio::time::Instant>,
+    // Levels with a quantity below this are treated as removed (zero keeps exact-zero semantics)
+    #[serde(skip)]
+    min_qty: Decimal,
+    // When a snapshot or update was last applied
+    #[serde(skip)]
+    updated_at: Option<tokio::time::Instant>,
+    // When a stream update last passed the sequence checks (snapshots don't count)
+    #[serde(skip)]
+    last_accepted_at: Option<tokio::time::Instant>,
+    // Bucket width of the grouped view; None keeps no grouped view
+    #[serde(skip)]
+    group_tick: Option<Decimal>,
+    // Quantity per group_tick bucket (bids floored, asks ceiled to a bucket edge),
+    // kept in step with the levels so grouped reads don't re-aggregate
+    #[serde(skip)]
+    grouped_bids: BTreeMap<Decimal, Decimal>,
+    #[serde(skip)]
+    grouped_asks: BTreeMap<Decimal, Decimal>,
+    // Most levels (bids + asks) held after any snapshot or update this session
+    #[serde(skip)]
+    peak_levels: usize,
+    // Accepted updates that moved the best bid or ask price, since startup
+    #[serde(skip)]
+    top_changes: u64,
+    // Stream updates that passed or failed the sequence checks, since startup.
+    // Stale pre-snapshot events and events before the first snapshot are not
+    // counted as rejected: dropping them is part of the sync procedure.
+    #[serde(skip)]
+    accepted_updates: u64,
+    #[serde(skip)]
+    rejected_updates: u64,
+    // Order flow imbalance of the last accepted update; see order_flow_contribution
+    #[serde(skip)]
+    last_ofi: Decimal,
+}
+
+impl OrderBook {
+    fn new(symbol: String) -> Self {
+        Self {
+            symbol,
+            last_update_id: 0,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            synced: false,
+            ticker_divergence_bps: None,
+            desynced_at: None,
+            min_qty: Decimal::ZERO,
+            updated_at: None,
+            last_accepted_at: None,
+            group_tick: None,
+            grouped_bids: BTreeMap::new(),
+            grouped_asks: BTreeMap::new(),
+            peak_levels: 0,
+            top_changes: 0,
+            accepted_updates: 0,
+            rejected_updates: 0,
+            last_ofi: Decimal::ZERO,
+        }
+    }
+
+    fn level_count(&self) -> usize {
+        self.bids.len() + self.asks.len()
+    }
+
+    fn record_peak_levels(&mut self) {
+        self.peak_levels = self.peak_levels.max(self.level_count());
+    }
+
+    // Zero removes a level per the protocol; dust below min_qty is treated the same
+    fn is_removed_qty(&self, qty: Decimal) -> bool {
+        qty.is_zero() || qty < self.min_qty
     }
 
     // Initialize from snapshot
@@ -141,79 +1497,228 @@ impl OrderBook {
         self.asks.clear();
         self.last_update_id = snapshot.last_update_id;
 
-        for bid in snapshot.bids {
-            let price = Decimal::from_str_exact(&bid[0]).unwrap_or_default();
-            let qty = Decimal::from_str_exact(&bid[1]).unwrap_or_default();
-            if qty > Decimal::ZERO {
+        for bid in &snapshot.bids {
+            let Some((price, qty)) = parse_level(bid, &self.symbol) else {
+                continue;
+            };
+            if !self.is_removed_qty(qty) {
                 self.bids.insert(price, qty);
             }
         }
 
-        for ask in snapshot.asks {
-            let price = Decimal::from_str_exact(&ask[0]).unwrap_or_default();
-            let qty = Decimal::from_str_exact(&ask[1]).unwrap_or_default();
-            if qty > Decimal::ZERO {
+        for ask in &snapshot.asks {
+            let Some((price, qty)) = parse_level(ask, &self.symbol) else {
+                continue;
+            };
+            if !self.is_removed_qty(qty) {
                 self.asks.insert(price, qty);
             }
         }
 
+        self.rebuild_grouped();
+        self.record_peak_levels();
         self.synced = false;
+        self.updated_at = Some(tokio::time::Instant::now());
         debug!("Applied snapshot for {} with lastUpdateId: {}", self.symbol, self.last_update_id);
     }
 
     // Apply depth update based on Binance's documentation
-    fn apply_update(&mut self, update: &DepthUpdateData) -> bool {
+    fn apply_update(&mut self, update: &DepthUpdateData) -> Result<(), OrderBookError> {
+        // Parse everything before touching the book: skipping a bad level would
+        // still advance the update id, leaving a book that drifted but claims
+        // to be synced
+        fn parse_side<'a>(side: &'a [[String; 2]], symbol: &str) -> Result<Vec<(Decimal, Decimal)>, &'a [String; 2]> {
+            side.iter().map(|level| parse_level(level, symbol).ok_or(level)).collect()
+        }
+        let levels = match (parse_side(&update.bids, &self.symbol), parse_side(&update.asks, &self.symbol)) {
+            (Ok(bids), Ok(asks)) => Ok((bids, asks)),
+            // Buffered events the snapshot already covers are dropped as stale either way
+            _ if !self.synced && update.final_update_id < self.last_update_id => Err(OrderBookError::StaleUpdate {
+                final_update_id: update.final_update_id,
+                last_update_id: self.last_update_id,
+            }),
+            (Err([price, qty]), _) | (_, Err([price, qty])) => Err(OrderBookError::InvalidLevel {
+                final_update_id: update.final_update_id,
+                price: price.clone(),
+                qty: qty.clone(),
+            }),
+        };
+        let sequenced = levels.and_then(|levels| {
+            self.accept_sequence(update.first_update_id, update.final_update_id, update.prev_final_update_id)?;
+            Ok(levels)
+        });
+        let (bids, asks) = match sequenced {
+            Ok(levels) => levels,
+            Err(e) => {
+                if self.last_update_id != 0 && !matches!(e, OrderBookError::StaleUpdate { .. }) {
+                    self.rejected_updates += 1;
+                }
+                return Err(e);
+            }
+        };
+        self.accepted_updates += 1;
+        let top_before = self.best_prices();
+        let (bid_before, ask_before) = (self.best_bid(), self.best_ask());
+
+        for (price, qty) in bids {
+            self.apply_level(true, price, qty);
+        }
+        for (price, qty) in asks {
+            self.apply_level(false, price, qty);
+        }
+
+        self.record_peak_levels();
+        if self.best_prices() != top_before {
+            self.top_changes += 1;
+        }
+        self.last_ofi = order_flow_contribution(bid_before, ask_before, self.best_bid(), self.best_ask());
+        Ok(())
+    }
+
+    // Replace the levels with a partial depth (depthN) payload. Each one is a
+    // complete top-N view, so there is no sequence to follow: the book is
+    // synced as soon as one arrives.
+    fn apply_partial(&mut self, update: &DepthUpdateData) {
+        let top_before = self.best_prices();
+        self.bids.clear();
+        self.asks.clear();
+        for bid in &update.bids {
+            if let Some((price, qty)) = parse_level(bid, &self.symbol) {
+                if !self.is_removed_qty(qty) {
+                    self.bids.insert(price, qty);
+                }
+            }
+        }
+        for ask in &update.asks {
+            if let Some((price, qty)) = parse_level(ask, &self.symbol) {
+                if !self.is_removed_qty(qty) {
+                    self.asks.insert(price, qty);
+                }
+            }
+        }
+        self.rebuild_grouped();
+        self.record_peak_levels();
+        if self.best_prices() != top_before {
+            self.top_changes += 1;
+        }
+        self.last_update_id = update.final_update_id;
+        self.synced = true;
+        let now = tokio::time::Instant::now();
+        self.updated_at = Some(now);
+        self.last_accepted_at = Some(now);
+    }
+
+    // Best bid and ask prices, for cheap before/after comparisons
+    fn best_prices(&self) -> (Option<Decimal>, Option<Decimal>) {
+        (self.bids.keys().next_back().copied(), self.asks.keys().next().copied())
+    }
+
+    // Sequence checks shared by stream updates and broadcast deltas; on success
+    // the book has advanced to final_update_id
+    fn accept_sequence(&mut self, first_update_id: u64, final_update_id: u64, prev_final_update_id: u64) -> Result<(), OrderBookError> {
         if !self.synced {
             // Step 4: Drop any event where u is < lastUpdateId in the snapshot
-            if update.final_update_id < self.last_update_id {
-                return false;
+            if final_update_id < self.last_update_id {
+                return Err(OrderBookError::StaleUpdate {
+                    final_update_id,
+                    last_update_id: self.last_update_id,
+                });
             }
 
             // Step 5: The first processed event should have U <= lastUpdateId AND u >= lastUpdateId
-            if update.first_update_id <= self.last_update_id && update.final_update_id >= self.last_update_id {
+            if first_update_id <= self.last_update_id && final_update_id >= self.last_update_id {
                 self.synced = true;
             } else {
-                return false;
+                return Err(OrderBookError::SnapshotGap {
+                    first_update_id,
+                    final_update_id,
+                    last_update_id: self.last_update_id,
+                });
             }
         } else {
             // Step 6: While listening to the stream, each new event's pu should be equal to the previous event's u
-            if update.prev_final_update_id != self.last_update_id {
-                return false;
+            if prev_final_update_id != self.last_update_id {
+                return Err(OrderBookError::SequenceGap {
+                    prev_final_update_id,
+                    last_update_id: self.last_update_id,
+                });
             }
         }
 
         // Update the last update ID
-        self.last_update_id = update.final_update_id;
+        self.last_update_id = final_update_id;
+        let now = tokio::time::Instant::now();
+        self.updated_at = Some(now);
+        self.last_accepted_at = Some(now);
+        Ok(())
+    }
 
-        // Process the bid updates
-        for bid in &update.bids {
-            let price = Decimal::from_str_exact(&bid[0]).unwrap_or_default();
-            let qty = Decimal::from_str_exact(&bid[1]).unwrap_or_default();
-            
-            if qty == Decimal::ZERO {
-                // Step 8: If the quantity is 0, remove the price level
-                self.bids.remove(&price);
-            } else {
-                // Step 7: Apply the update
-                self.bids.insert(price, qty);
-            }
+    fn apply_level(&mut self, is_bid: bool, price: Decimal, qty: Decimal) {
+        let removed = self.is_removed_qty(qty);
+        let levels = if is_bid { &mut self.bids } else { &mut self.asks };
+        let old = if removed {
+            // Step 8: If the quantity is 0 (or below min_qty), remove the price level
+            levels.remove(&price)
+        } else {
+            // Step 7: Apply the update
+            levels.insert(price, qty)
+        };
+        self.regroup(is_bid, price, old);
+    }
+
+    // Consumer side of the delta broadcast: a synced book seeded from an
+    // OrderBookSnapshot event (or a get_order_book copy via apply_delta)
+    #[allow(dead_code)]
+    fn from_snapshot_event(snapshot: &OrderBookUpdateEvent) -> Self {
+        let mut book = Self::new(snapshot.symbol.clone());
+        book.last_update_id = snapshot.last_update_id;
+        book.bids.extend(snapshot.bids.iter().copied().filter(|(_, qty)| !qty.is_zero()));
+        book.asks.extend(snapshot.asks.iter().copied().filter(|(_, qty)| !qty.is_zero()));
+        book.synced = true;
+        book.updated_at = Some(tokio::time::Instant::now());
+        book.record_peak_levels();
+        book
+    }
+
+    // Apply one broadcast delta with the same sequence rules as apply_update.
+    // Deltas already covered by the book (final_update_id <= last_update_id)
+    // fail with StaleUpdate and leave it untouched, so callers seeding mid-feed
+    // can skip them.
+    #[allow(dead_code)]
+    fn apply_delta(&mut self, delta: &OrderBookDeltaEvent) -> Result<(), OrderBookError> {
+        if self.synced && delta.final_update_id <= self.last_update_id {
+            return Err(OrderBookError::StaleUpdate {
+                final_update_id: delta.final_update_id,
+                last_update_id: self.last_update_id,
+            });
         }
+        self.accept_sequence(delta.first_update_id, delta.final_update_id, delta.prev_final_update_id)?;
+        for &(price, qty) in &delta.bids {
+            self.apply_level(true, price, qty);
+        }
+        for &(price, qty) in &delta.asks {
+            self.apply_level(false, price, qty);
+        }
+        self.record_peak_levels();
+        Ok(())
+    }
 
-        // Process the ask updates
-        for ask in &update.asks {
-            let price = Decimal::from_str_exact(&ask[0]).unwrap_or_default();
-            let qty = Decimal::from_str_exact(&ask[1]).unwrap_or_default();
-            
-            if qty == Decimal::ZERO {
-                // Step 8: If the quantity is 0, remove the price level
-                self.asks.remove(&price);
-            } else {
-                // Step 7: Apply the update
-                self.asks.insert(price, qty);
+    // Rebuild a book from a snapshot event and the deltas that followed it,
+    // skipping deltas the snapshot already covers. Any other sequence error is
+    // returned; the consumer should wait for the next snapshot event.
+    #[allow(dead_code)]
+    fn from_delta_feed<'a>(
+        snapshot: &OrderBookUpdateEvent,
+        deltas: impl IntoIterator<Item = &'a OrderBookDeltaEvent>,
+    ) -> Result<Self, OrderBookError> {
+        let mut book = Self::from_snapshot_event(snapshot);
+        for delta in deltas {
+            match book.apply_delta(delta) {
+                Ok(()) | Err(OrderBookError::StaleUpdate { .. }) => {}
+                Err(e) => return Err(e),
             }
         }
-
-        true
+        Ok(book)
     }
 
     // Get the best bid (highest price)
@@ -226,10 +1731,269 @@ impl OrderBook {
         self.asks.iter().next().map(|(k, v)| (*k, *v))
     }
 
+    // Best bid and ask together; None if either side is empty
+    fn top_of_book(&self) -> Option<TopOfBook> {
+        let (bid_price, bid_qty) = self.best_bid()?;
+        let (ask_price, ask_qty) = self.best_ask()?;
+        Some(TopOfBook {
+            bid_price,
+            bid_qty,
+            ask_price,
+            ask_qty,
+            last_update_id: self.last_update_id,
+        })
+    }
+
+    // Best ask minus best bid
+    fn spread(&self) -> Option<Decimal> {
+        let (bid, _) = self.best_bid()?;
+        let (ask, _) = self.best_ask()?;
+        Some(ask - bid)
+    }
+
+    // Mid price between best bid and best ask
+    fn mid_price(&self) -> Option<Decimal> {
+        let (bid, _) = self.best_bid()?;
+        let (ask, _) = self.best_ask()?;
+        Some((bid + ask) / Decimal::TWO)
+    }
+
+    // Resting quantity at exactly `price` on `side`; zero if there is no level
+    #[allow(dead_code)]
+    fn quantity_at(&self, side: BookSide, price: Decimal) -> Decimal {
+        let levels = match side {
+            BookSide::Bid => &self.bids,
+            BookSide::Ask => &self.asks,
+        };
+        levels.get(&price).copied().unwrap_or(Decimal::ZERO)
+    }
+
+    // Volume-weighted average fill price for a market order of `side` and
+    // `qty`, walking the opposite side of the book (Buy walks asks, Sell walks
+    // bids). None if the book can't fill the size.
+    #[allow(dead_code)]
+    fn market_impact(&self, side: Side, qty: Decimal) -> Option<Decimal> {
+        if qty <= Decimal::ZERO {
+            return None;
+        }
+        let levels: Box<dyn Iterator<Item = (&Decimal, &Decimal)>> = match side {
+            Side::Buy => Box::new(self.asks.iter()),
+            Side::Sell => Box::new(self.bids.iter().rev()),
+        };
+
+        let mut remaining = qty;
+        let mut notional = Decimal::ZERO;
+        for (price, level_qty) in levels {
+            let fill = remaining.min(*level_qty);
+            notional += fill * price;
+            remaining -= fill;
+            if remaining.is_zero() {
+                return Some(notional / qty);
+            }
+        }
+        None
+    }
+
+    // Twice the distance between the fill price for `qty` and the mid; the
+    // real round-trip cost of crossing the book at that size. `side` is the
+    // market order's, as in market_impact.
+    #[allow(dead_code)]
+    fn effective_spread(&self, side: Side, qty: Decimal) -> Option<Decimal> {
+        let fill_price = self.market_impact(side, qty)?;
+        let mid = self.mid_price()?;
+        Some(Decimal::TWO * (fill_price - mid).abs())
+    }
+
+    // Quantity a market order of `side` must consume to move the best price on
+    // the opposite side (asks for Buy, bids for Sell) by `bps` basis points:
+    // everything resting strictly inside the threshold, so the next best level
+    // sits at or beyond it. The inverse of market_impact. None if no level
+    // reaches the threshold.
+    #[allow(dead_code)]
+    fn depth_to_move(&self, side: Side, bps: Decimal) -> Option<Decimal> {
+        if bps < Decimal::ZERO {
+            return None;
+        }
+        let offset = |best: Decimal| best * bps / Decimal::from(10_000);
+        let (threshold, levels): (Decimal, Box<dyn Iterator<Item = (&Decimal, &Decimal)>>) = match side {
+            Side::Buy => (self.best_ask().map(|(best, _)| best + offset(best))?, Box::new(self.asks.iter())),
+            Side::Sell => (self.best_bid().map(|(best, _)| best - offset(best))?, Box::new(self.bids.iter().rev())),
+        };
+
+        let mut consumed = Decimal::ZERO;
+        for (price, qty) in levels {
+            let inside = match side {
+                Side::Buy => *price < threshold,
+                Side::Sell => *price > threshold,
+            };
+            if !inside {
+                return Some(consumed);
+            }
+            consumed += qty;
+        }
+        None
+    }
+
+    // Quantity imbalance over the top `levels` per side, with level i weighted by
+    // decay^i so levels near the touch dominate. Returns a value in [-1, 1],
+    // positive when bids outweigh asks, or None for a decay outside (0, 1].
+    #[allow(dead_code)]
+    fn weighted_imbalance(&self, decay: Decimal, levels: usize) -> Option<Decimal> {
+        if decay <= Decimal::ZERO || decay > Decimal::ONE {
+            return None;
+        }
+        let weighted = |side: Vec<(Decimal, Decimal)>| {
+            let mut weight = Decimal::ONE;
+            let mut total = Decimal::ZERO;
+            for (_, qty) in side {
+                total += qty * weight;
+                weight *= decay;
+            }
+            total
+        };
+        let bid_qty = weighted(self.top_bids(levels));
+        let ask_qty = weighted(self.top_asks(levels));
+        let total = bid_qty + ask_qty;
+        if total.is_zero() {
+            return None;
+        }
+        Some((bid_qty - ask_qty) / total)
+    }
+
+    // Notional (price * qty) imbalance over the top `levels` per side, in
+    // [-1, 1] and positive when bids outweigh asks. Unlike weighted_imbalance it
+    // weighs money rather than size, so it compares across price scales.
+    // None for an empty book.
+    #[allow(dead_code)]
+    fn notional_imbalance(&self, levels: usize) -> Option<Decimal> {
+        let side_notional = |side: &mut dyn Iterator<Item = (&Decimal, &Decimal)>| {
+            side.take(levels).map(|(price, qty)| price * qty).sum::<Decimal>()
+        };
+        let bid_notional = side_notional(&mut self.iter_bids());
+        let ask_notional = side_notional(&mut self.iter_asks());
+        let total = bid_notional + ask_notional;
+        if total.is_zero() {
+            return None;
+        }
+        Some((bid_notional - ask_notional) / total)
+    }
+
+    // Total price * qty over the top `levels` per side
+    fn notional(&self, levels: usize) -> Decimal {
+        self.bids.iter().rev().take(levels)
+            .chain(self.asks.iter().take(levels))
+            .map(|(price, qty)| price * qty)
+            .sum()
+    }
+
+    // Quantity-weighted average price over the top `levels` per side combined:
+    // a fair-value estimate that, unlike mid or micro-price, accounts for depth.
+    // None for an empty book.
+    #[allow(dead_code)]
+    fn liquidity_weighted_price(&self, levels: usize) -> Option<Decimal> {
+        let (notional, qty) = self.bids.iter().rev().take(levels)
+            .chain(self.asks.iter().take(levels))
+            .fold((Decimal::ZERO, Decimal::ZERO), |(notional, total), (price, qty)| {
+                (notional + price * qty, total + qty)
+            });
+        if qty.is_zero() {
+            return None;
+        }
+        Some(notional / qty)
+    }
+
+    // Drop zero-quantity and dust levels, and levels further than max_distance_bps from the mid.
+    // Returns the number of levels removed.
+    fn compact(&mut self, max_distance_bps: Decimal) -> usize {
+        let before = self.bids.len() + self.asks.len();
+
+        let min_qty = self.min_qty;
+        self.bids.retain(|_, qty| !qty.is_zero() && *qty >= min_qty);
+        self.asks.retain(|_, qty| !qty.is_zero() && *qty >= min_qty);
+
+        if let Some(mid) = self.mid_price() {
+            let distance = mid * max_distance_bps / Decimal::from(10_000);
+            let (floor, ceiling) = (mid - distance, mid + distance);
+            self.bids.retain(|price, _| *price >= floor);
+            self.asks.retain(|price, _| *price <= ceiling);
+        }
+
+        let removed = before - (self.bids.len() + self.asks.len());
+        if removed > 0 {
+            self.rebuild_grouped();
+        }
+        removed
+    }
+
+    // Start (or stop, with None) maintaining the grouped view at this tick
+    fn set_group_tick(&mut self, tick: Option<Decimal>) {
+        if self.group_tick != tick {
+            self.group_tick = tick;
+            self.rebuild_grouped();
+        }
+    }
+
+    // Recompute the grouped view from the levels, after bulk changes
+    fn rebuild_grouped(&mut self) {
+        self.grouped_bids.clear();
+        self.grouped_asks.clear();
+        let Some(tick) = self.group_tick else {
+            return;
+        };
+        for (price, qty) in &self.bids {
+            *self.grouped_bids.entry(group_bucket(tick, *price, true)).or_insert(Decimal::ZERO) += qty;
+        }
+        for (price, qty) in &self.asks {
+            *self.grouped_asks.entry(group_bucket(tick, *price, false)).or_insert(Decimal::ZERO) += qty;
+        }
+    }
+
+    // Carry one level's change in quantity into its grouped bucket
+    fn regroup(&mut self, is_bid: bool, price: Decimal, old: Option<Decimal>) {
+        let Some(tick) = self.group_tick else {
+            return;
+        };
+        let (levels, grouped) = if is_bid {
+            (&self.bids, &mut self.grouped_bids)
+        } else {
+            (&self.asks, &mut self.grouped_asks)
+        };
+        let delta = levels.get(&price).copied().unwrap_or(Decimal::ZERO) - old.unwrap_or(Decimal::ZERO);
+        if delta.is_zero() {
+            return;
+        }
+        let bucket = group_bucket(tick, price, is_bid);
+        let total = grouped.entry(bucket).or_insert(Decimal::ZERO);
+        *total += delta;
+        if *total <= Decimal::ZERO {
+            grouped.remove(&bucket);
+        }
+    }
+
+    // Top N grouped buckets per side, best first; None without a grouped view
+    #[allow(dead_code)]
+    fn grouped_levels(&self, n: usize) -> Option<GroupedBook> {
+        Some(GroupedBook {
+            tick: self.group_tick?,
+            bids: self.grouped_bids.iter().rev().take(n).map(|(k, v)| (*k, *v)).collect(),
+            asks: self.grouped_asks.iter().take(n).map(|(k, v)| (*k, *v)).collect(),
+        })
+    }
+
+    // All bids, best (highest) first, without allocating. Hold the book via
+    // with_order_book to stream the full depth under one read guard.
+    fn iter_bids(&self) -> impl Iterator<Item = (&Decimal, &Decimal)> + '_ {
+        self.bids.iter().rev()
+    }
+
+    // All asks, best (lowest) first
+    fn iter_asks(&self) -> impl Iterator<Item = (&Decimal, &Decimal)> + '_ {
+        self.asks.iter()
+    }
+
     // Get a sorted vec of top N bids
     fn top_bids(&self, n: usize) -> Vec<(Decimal, Decimal)> {
-        self.bids.iter()
-            .rev()
+        self.iter_bids()
             .take(n)
             .map(|(k, v)| (*k, *v))
             .collect()
@@ -237,11 +2001,49 @@ impl OrderBook {
 
     // Get a sorted vec of top N asks
     fn top_asks(&self, n: usize) -> Vec<(Decimal, Decimal)> {
-        self.asks.iter()
+        self.iter_asks()
             .take(n)
             .map(|(k, v)| (*k, *v))
             .collect()
     }
+
+    // Top N bids and asks, best first, in stack arrays instead of Vecs; slots
+    // past the depth of a side are None. For polling loops, e.g.
+    // with_order_book(symbol, OrderBook::top_of_book_array::<5>).
+    #[allow(dead_code, clippy::type_complexity)]
+    fn top_of_book_array<const N: usize>(&self) -> ([Option<(Decimal, Decimal)>; N], [Option<(Decimal, Decimal)>; N]) {
+        let (mut bids, mut asks) = ([None; N], [None; N]);
+        for (slot, (price, qty)) in bids.iter_mut().zip(self.iter_bids()) {
+            *slot = Some((*price, *qty));
+        }
+        for (slot, (price, qty)) in asks.iter_mut().zip(self.iter_asks()) {
+            *slot = Some((*price, *qty));
+        }
+        (bids, asks)
+    }
+
+    // Hash key for to_redis_fields, e.g. "book:BTCUSDT"
+    #[allow(dead_code)]
+    fn redis_key(&self) -> String {
+        format!("book:{}", self.symbol)
+    }
+
+    // Top `levels` per side as field/value pairs for an HSET on redis_key():
+    // "bid:0:price", "bid:0:qty", ... best first, plus "last_update_id" and
+    // "synced". Prefix the fields with redis_key() and ':' for flat keys.
+    #[allow(dead_code)]
+    fn to_redis_fields(&self, levels: usize) -> Vec<(String, String)> {
+        let mut fields = Vec::with_capacity(4 * levels + 2);
+        for (side, top) in [("bid", self.top_bids(levels)), ("ask", self.top_asks(levels))] {
+            for (i, (price, qty)) in top.into_iter().enumerate() {
+                fields.push((format!("{}:{}:price", side, i), price.to_string()));
+                fields.push((format!("{}:{}:qty", side, i), qty.to_string()));
+            }
+        }
+        fields.push(("last_update_id".to_string(), self.last_update_id.to_string()));
+        fields.push(("synced".to_string(), self.synced.to_string()));
+        fields
+    }
 }
 
 fn decimal_from_str<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
@@ -249,7 +2051,127 @@ where
     D: serde::Deserializer<'de>,
 {
     let s = String::deserialize(deserializer)?;
-    Decimal::from_str_exact(&s).map_err(serde::de::Error::custom)
+    Decimal::from_str_exact(&s).map_err(|e| {
+        if is_decimal_overflow(&e) {
+            serde::de::Error::custom(format!("value {:?} exceeds Decimal range: {}", s, e))
+        } else {
+            serde::de::Error::custom(format!("invalid decimal {:?}: {}", s, e))
+        }
+    })
+}
+
+// As decimal_from_str, but an empty string is None
+fn optional_decimal_from_str<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    if s.is_empty() {
+        return Ok(None);
+    }
+    Decimal::from_str_exact(&s)
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("invalid decimal {:?}: {}", s, e)))
+}
+
+// True when the value was well-formed but outside what Decimal can represent,
+// as opposed to malformed input
+fn is_decimal_overflow(e: &rust_decimal::Error) -> bool {
+    match e {
+        rust_decimal::Error::ExceedsMaximumPossibleValue
+        | rust_decimal::Error::LessThanMinimumPossibleValue
+        | rust_decimal::Error::Underflow
+        | rust_decimal::Error::ScaleExceedsMaximumPrecision(_) => true,
+        rust_decimal::Error::ErrorString(msg) => msg.contains("overflow"),
+        _ => false,
+    }
+}
+
+// Order flow imbalance between two consecutive book states (Cont, Kukanov and
+// Stoikov): bid-side demand minus ask-side supply. A best bid that rose or held
+// adds its new quantity, one that fell or held removes its old quantity, and the
+// ask side mirrors it. Zero when either state has an empty side.
+fn order_flow_contribution(
+    bid_before: Option<(Decimal, Decimal)>,
+    ask_before: Option<(Decimal, Decimal)>,
+    bid_after: Option<(Decimal, Decimal)>,
+    ask_after: Option<(Decimal, Decimal)>,
+) -> Decimal {
+    let (Some((bid_price, bid_qty)), Some((ask_price, ask_qty))) = (bid_after, ask_after) else {
+        return Decimal::ZERO;
+    };
+    let (Some((prev_bid_price, prev_bid_qty)), Some((prev_ask_price, prev_ask_qty))) = (bid_before, ask_before) else {
+        return Decimal::ZERO;
+    };
+    let mut ofi = Decimal::ZERO;
+    if bid_price >= prev_bid_price {
+        ofi += bid_qty;
+    }
+    if bid_price <= prev_bid_price {
+        ofi -= prev_bid_qty;
+    }
+    if ask_price <= prev_ask_price {
+        ofi -= ask_qty;
+    }
+    if ask_price >= prev_ask_price {
+        ofi += prev_ask_qty;
+    }
+    ofi
+}
+
+// Grouped-view bucket of a price: bids round down and asks round up to a multiple
+// of `tick`, so a bucket never looks better than the levels in it
+fn group_bucket(tick: Decimal, price: Decimal, is_bid: bool) -> Decimal {
+    let ticks = price / tick;
+    if is_bid {
+        ticks.floor() * tick
+    } else {
+        ticks.ceil() * tick
+    }
+}
+
+// Parse a [price, qty] level, logging why it failed. Unparseable levels are
+// never defaulted to zero, which would insert a bogus zero price into the book:
+// snapshot levels are skipped and depth updates carrying one are rejected.
+fn parse_level(level: &[String; 2], symbol: &str) -> Option<(Decimal, Decimal)> {
+    let parse = |raw: &str| {
+        Decimal::from_str_exact(raw).map_err(|e| {
+            if is_decimal_overflow(&e) {
+                error!("{} level value {:?} exceeds Decimal range: {}", symbol, raw, e);
+            } else {
+                warn!("{} level value {:?} is not a valid decimal: {}", symbol, raw, e);
+            }
+        })
+    };
+    Some((parse(&level[0]).ok()?, parse(&level[1]).ok()?))
+}
+
+// "qty x price" pairs, best first
+fn format_ladder<P: std::fmt::Display, Q: std::fmt::Display>(levels: impl Iterator<Item = (P, Q)>) -> String {
+    levels.map(|(price, qty)| format!("{} x {}", qty, price)).collect::<Vec<_>>().join(", ")
+}
+
+// Diagnostic dump of the book at the moment it rejected an update, next to the
+// update itself. A rejected update is not applied, so the book is exactly the
+// state the update failed to follow.
+fn log_desync_ladder(book: &OrderBook, update: &DepthUpdateData, levels: usize) {
+    let update_side = |side: &[[String; 2]]| format_ladder(side.iter().take(levels).map(|[p, q]| (p, q)));
+    warn!(
+        "{} book before rejected update (u={}): bids [{}] asks [{}]",
+        book.symbol,
+        book.last_update_id,
+        format_ladder(book.top_bids(levels).into_iter()),
+        format_ladder(book.top_asks(levels).into_iter())
+    );
+    warn!(
+        "{} rejected update U={} u={} pu={}: bids [{}] asks [{}]",
+        book.symbol,
+        update.first_update_id,
+        update.final_update_id,
+        update.prev_final_update_id,
+        update_side(&update.bids),
+        update_side(&update.asks)
+    );
 }
 
 #[derive(Debug, Deserialize)]
@@ -258,282 +2180,3293 @@ struct StreamMessage {
     data: serde_json::Value,
 }
 
-// Cache for storing latest market data
-struct MarketDataCache {
-    book_tickers: DashMap<String, BookTickerData>,
-    last_trades: DashMap<String, TradeData>,
-    order_books: DashMap<String, OrderBook>,
+// Tracks gaps between consecutive messages on one stream
+#[derive(Debug)]
+struct InterArrivalTracker {
+    last_seen: Option<tokio::time::Instant>,
+    count: u64,
+    min: Duration,
+    max: Duration,
+    total: Duration,
+    // Most recent gaps, used for the percentile estimate
+    recent: VecDeque<Duration>,
 }
 
-impl MarketDataCache {
+// Point-in-time summary of a stream's inter-arrival times
+#[derive(Debug, Clone)]
+struct InterArrivalStats {
+    count: u64,
+    min: Duration,
+    max: Duration,
+    mean: Duration,
+    // p99 over the most recent INTER_ARRIVAL_WINDOW gaps
+    p99: Duration,
+}
+
+impl InterArrivalTracker {
     fn new() -> Self {
         Self {
-            book_tickers: DashMap::with_capacity(100),
-            last_trades: DashMap::with_capacity(100),
-            order_books: DashMap::with_capacity(100),
-        }
-    }
-
-    fn batch_update_book_tickers(&self, tickers: Vec<BookTickerData>) {
-        for ticker in tickers {
-            let symbol = ticker.symbol.to_uppercase();
-            self.book_tickers.insert(symbol, ticker);
+            last_seen: None,
+            count: 0,
+            min: Duration::MAX,
+            max: Duration::ZERO,
+            total: Duration::ZERO,
+            recent: VecDeque::with_capacity(INTER_ARRIVAL_WINDOW),
         }
     }
 
-    fn batch_update_trades(&self, trades: Vec<TradeData>) {
-        for trade in trades {
-            let symbol = trade.symbol.to_uppercase();
-            self.last_trades.insert(symbol, trade);
+    fn record(&mut self, now: tokio::time::Instant) {
+        if let Some(prev) = self.last_seen.replace(now) {
+            let gap = now.saturating_duration_since(prev);
+            self.count += 1;
+            self.min = self.min.min(gap);
+            self.max = self.max.max(gap);
+            self.total += gap;
+            if self.recent.len() >= INTER_ARRIVAL_WINDOW {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(gap);
         }
     }
 
-    fn update_order_book(&self, update: DepthUpdateData) {
-        let symbol = update.symbol.to_uppercase();
-        
-        match self.order_books.entry(symbol.clone()) {
-            dashmap::mapref::entry::Entry::Occupied(mut entry) => {
-                let book = entry.get_mut();
-                if !book.apply_update(&update) {
-                    warn!("Order book {} needs resyncing", symbol);
-                    // Mark for resync
-                    book.synced = false;
-                }
-            }
-            dashmap::mapref::entry::Entry::Vacant(entry) => {
-                let mut book = OrderBook::new(symbol.clone());
-                if !book.apply_update(&update) {
-                    debug!("Created new order book for {}, waiting for sync", symbol);
-                }
-                entry.insert(book);
-            }
+    fn stats(&self) -> Option<InterArrivalStats> {
+        if self.count == 0 {
+            return None;
         }
+        let mut sorted: Vec<Duration> = self.recent.iter().copied().collect();
+        sorted.sort_unstable();
+        let p99_index = ((sorted.len() * 99).div_ceil(100)).saturating_sub(1);
+        Some(InterArrivalStats {
+            count: self.count,
+            min: self.min,
+            max: self.max,
+            mean: Duration::from_nanos((self.total.as_nanos() / self.count as u128) as u64),
+            p99: sorted[p99_index],
+        })
     }
 }
 
-// WebSocket client for Binance API
-struct BinanceWebSocketClient {
-    ws_stream: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    symbols: Vec<String>,
-    cache: Arc<MarketDataCache>,
-    reconnect_attempts: u8,
-    http_client: HttpClient,
+// Fixed-bucket histogram of durations
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+struct DurationHistogram {
+    // Upper bounds of each bucket; the last bucket is unbounded
+    bounds: &'static [Duration],
+    counts: Vec<u64>,
+    count: u64,
+    total: Duration,
+    max: Duration,
 }
 
-impl BinanceWebSocketClient {
-    fn new(symbols: Vec<String>) -> Self {
+impl DurationHistogram {
+    fn new() -> Self {
         Self {
-            ws_stream: None,
-            symbols,
-            cache: Arc::new(MarketDataCache::new()),
-            reconnect_attempts: 0,
-            http_client: HttpClient::new(),
+            bounds: RESYNC_HISTOGRAM_BOUNDS,
+            counts: vec![0; RESYNC_HISTOGRAM_BOUNDS.len() + 1],
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
         }
     }
 
-    async fn fetch_order_book_snapshot(&self, symbol: &str) -> Result<OrderBookSnapshot, WebSocketError> {
-        let url = format!(
-            "{}/fapi/v1/depth?symbol={}&limit={}",
-            BINANCE_API_URL, symbol.to_uppercase(), ORDER_BOOK_DEPTH
-        );
-        
-        debug!("Fetching order book snapshot for {}", symbol);
-        let response = self.http_client.get(&url).send().await?;
-        let snapshot: OrderBookSnapshot = response.json().await?;
-        
-        Ok(snapshot)
+    fn record(&mut self, value: Duration) {
+        let bucket = self.bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.total += value;
+        self.max = self.max.max(value);
+    }
+}
+
+// Top-N view of a book after an accepted update. Bids are best-first
+// (descending), asks best-first (ascending).
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+struct OrderBookUpdateEvent {
+    symbol: String,
+    last_update_id: u64,
+    bids: Vec<(Decimal, Decimal)>,
+    asks: Vec<(Decimal, Decimal)>,
+}
+
+impl OrderBookUpdateEvent {
+    // The only place event ladders are built, so ordering stays canonical
+    fn from_book(book: &OrderBook, depth: usize) -> Self {
+        Self {
+            symbol: book.symbol.clone(),
+            last_update_id: book.last_update_id,
+            bids: book.top_bids(depth),
+            asks: book.top_asks(depth),
+        }
+    }
+}
+
+// Levels changed by one accepted depth update; a zero quantity removes the
+// level. Consumers apply deltas in order on top of an OrderBookSnapshot event,
+// checking prev_final_update_id against the last applied final_update_id.
+// Subscribers joining after sync can seed from get_order_book instead and skip
+// deltas with final_update_id <= its last_update_id. OrderBook::from_delta_feed
+// and apply_delta implement these rules.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+struct OrderBookDeltaEvent {
+    symbol: String,
+    first_update_id: u64,
+    final_update_id: u64,
+    prev_final_update_id: u64,
+    bids: Vec<(Decimal, Decimal)>,
+    asks: Vec<(Decimal, Decimal)>,
+}
+
+impl OrderBookDeltaEvent {
+    // Delta for an update just applied to `book`, named by the book's cache key.
+    // Levels the book treats as removed (zero, or dust below its min_qty) go out
+    // with a zero quantity, so replicas without that filter still match it.
+    // apply_update rejects updates with unparseable levels, so all of these parse.
+    fn from_update(book: &OrderBook, update: &DepthUpdateData) -> Self {
+        let levels = |side: &[[String; 2]]| {
+            side.iter()
+                .filter_map(|[price, qty]| Some((Decimal::from_str_exact(price).ok()?, Decimal::from_str_exact(qty).ok()?)))
+                .map(|(price, qty)| (price, if book.is_removed_qty(qty) { Decimal::ZERO } else { qty }))
+                .collect()
+        };
+        Self {
+            symbol: book.symbol.clone(),
+            first_update_id: update.first_update_id,
+            final_update_id: update.final_update_id,
+            prev_final_update_id: update.prev_final_update_id,
+            bids: levels(&update.bids),
+            asks: levels(&update.asks),
+        }
+    }
+}
+
+// Events broadcast to subscribers of the cache
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+enum MarketEvent {
+    OrderBookUpdate(OrderBookUpdateEvent),
+    // Full book when it (re)syncs in delta mode; later deltas build on it
+    OrderBookSnapshot(OrderBookUpdateEvent),
+    OrderBookDelta(OrderBookDeltaEvent),
+    // Book tickers and trades as they are flushed into the cache
+    BookTicker(BookTickerData),
+    Trade(TradeData),
+    // Last bookTicker received before a reconnect, re-sent once the new connection
+    // is up (with replay_book_tickers_on_reconnect) so consumers aren't left without
+    // a quote. Pre-reconnect data; the symbol's next BookTicker supersedes it.
+    StaleBookTicker(BookTickerData),
+    // Connection lifecycle, so subscribers can tell a feed gap from a quiet market
+    Connected { streams: usize },
+    Disconnected { reason: String },
+    Reconnecting { attempt: u8, delay: Duration },
+}
+
+// On-disk checkpoint of the order books, used for warm starts
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheCheckpoint {
+    saved_at_ms: u64,
+    order_books: Vec<OrderBook>,
+}
+
+fn is_bincode_path(path: &std::path::Path) -> bool {
+    matches!(path.extension().and_then(|e| e.to_str()), Some("bin" | "bincode"))
+}
+
+// Destination of archive_trades
+enum TradeArchive {
+    Ndjson(std::path::PathBuf),
+    #[cfg(feature = "parquet")]
+    Parquet(std::path::PathBuf),
+}
+
+impl TradeArchive {
+    fn new(config: &ClientConfig, dir: std::path::PathBuf) -> Self {
+        match config.trade_archive_format {
+            #[cfg(feature = "parquet")]
+            TradeArchiveFormat::Parquet => TradeArchive::Parquet(dir),
+            // validate() rejects parquet without the feature
+            _ => TradeArchive::Ndjson(dir.join("trades.ndjson")),
+        }
+    }
+
+    // Durably write a batch, all or nothing: on Ok it is on disk, on Err none
+    // of it is, so retrying the whole batch never duplicates trades. Blocking.
+    fn write(&self, trades: &[TradeData]) -> Result<(), WebSocketError> {
+        match self {
+            TradeArchive::Ndjson(path) => {
+                use std::io::Write;
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let mut lines = Vec::new();
+                for trade in trades {
+                    serde_json::to_writer(&mut lines, trade)?;
+                    lines.push(b'\n');
+                }
+                let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+                let len = file.metadata()?.len();
+                if let Err(e) = file.write_all(&lines).and_then(|_| file.sync_data()) {
+                    // Cut off whatever part of the batch made it in
+                    let _ = file.set_len(len);
+                    return Err(e.into());
+                }
+                Ok(())
+            }
+            #[cfg(feature = "parquet")]
+            TradeArchive::Parquet(dir) => {
+                let path = parquet_sink::write_file(dir, trades)?;
+                debug!("Archived {} trades to {}", trades.len(), path.display());
+                Ok(())
+            }
+        }
+    }
+}
+
+// Write through a temp file so a crash mid-write never leaves a truncated file behind
+fn write_atomically(path: &std::path::Path, bytes: &[u8]) -> Result<(), WebSocketError> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Cache for storing latest market data
+// Market data of one or more markets, keyed by (market, symbol). Each cache
+// writes under its own config.market, so clients on different markets can
+// share one store (see share_market_store) and read each other's data.
+struct MarketStore {
+    book_tickers: DashMap<MarketKey, BookTickerData>,
+    last_trades: DashMap<MarketKey, TradeData>,
+    mark_prices: DashMap<MarketKey, MarkPriceData>,
+    // Last trade_buffer_size trades per symbol, oldest first
+    recent_trades: DashMap<MarketKey, VecDeque<TradeData>>,
+    // The same trades annotated with the prevailing quote, when trade_quote_source is set
+    quoted_trades: DashMap<MarketKey, VecDeque<QuotedTrade>>,
+    // Keyed by book key (see order_book_key) rather than plain symbol
+    order_books: DashMap<MarketKey, OrderBook>,
+    // Highest bookTicker update id seen per symbol this session
+    book_ticker_high_water: DashMap<MarketKey, u64>,
+    // Trading rules from exchangeInfo
+    symbol_info: DashMap<MarketKey, SymbolInfo>,
+    // When each symbol's data of a stream type was last updated, for the stale_after check
+    last_updated: DashMap<(MarketKey, StreamType), tokio::time::Instant>,
+    // Markets of the caches writing to this store, at most one cache each
+    markets: DashSet<Market>,
+}
+
+impl MarketStore {
+    fn new(shards: Option<usize>) -> Self {
+        // Maps written on every frame and read by clients, sized per cache_shards
+        fn symbol_map<K: Eq + std::hash::Hash, V>(capacity: usize, shards: Option<usize>) -> DashMap<K, V> {
+            match shards {
+                Some(shards) => DashMap::with_capacity_and_shard_amount(capacity, shards),
+                None => DashMap::with_capacity(capacity),
+            }
+        }
+        Self {
+            book_tickers: symbol_map(100, shards),
+            last_trades: symbol_map(100, shards),
+            mark_prices: symbol_map(100, shards),
+            recent_trades: symbol_map(0, shards),
+            quoted_trades: DashMap::new(),
+            order_books: symbol_map(100, shards),
+            book_ticker_high_water: DashMap::with_capacity(100),
+            symbol_info: DashMap::new(),
+            last_updated: symbol_map(0, shards),
+            markets: DashSet::new(),
+        }
+    }
+}
+
+// Symbol argument of the cache readers. A bare symbol reads the cache's own
+// market; a (Market, symbol) pair reads any market sharing its store.
+#[derive(Debug, Clone, Copy)]
+struct MarketSymbol<'a> {
+    market: Option<Market>,
+    symbol: &'a str,
+}
+
+impl<'a> From<&'a str> for MarketSymbol<'a> {
+    fn from(symbol: &'a str) -> Self {
+        Self { market: None, symbol }
+    }
+}
+
+impl<'a> From<&'a String> for MarketSymbol<'a> {
+    fn from(symbol: &'a String) -> Self {
+        Self { market: None, symbol }
+    }
+}
+
+impl<'a> From<(Market, &'a str)> for MarketSymbol<'a> {
+    fn from((market, symbol): (Market, &'a str)) -> Self {
+        Self { market: Some(market), symbol }
+    }
+}
+
+struct MarketDataCache {
+    store: Arc<MarketStore>,
+    // Trades received but not yet written to the trade archive, oldest first
+    unarchived_trades: Mutex<Vec<TradeData>>,
+    // Inter-arrival timing per stream name (e.g. btcusdt@depth), when enabled
+    inter_arrival: DashMap<String, InterArrivalTracker>,
+    // Time from a rejected update to the book syncing again, per symbol
+    resync_durations: DashMap<String, DurationHistogram>,
+    // Snapshot retries for books that missed their sync deadline; cleared on sync
+    sync_retries: DashMap<String, u32>,
+    // lastUpdateId of each synced book at the previous consistency check
+    checked_update_ids: DashMap<String, u64>,
+    // When each synced book was first seen with an empty side; cleared once both sides have levels
+    empty_side_since: DashMap<String, tokio::time::Instant>,
+    // Recent top-N notional samples per book, oldest first, for is_thin
+    notional_history: DashMap<String, VecDeque<Decimal>>,
+    // Spread samples per book within spread_history, oldest first
+    spread_history: DashMap<String, VecDeque<(tokio::time::Instant, Decimal)>>,
+    // OFI contribution of each accepted update per book within ofi_window, oldest first
+    ofi_history: DashMap<String, VecDeque<(tokio::time::Instant, Decimal)>>,
+    events: EventLog,
+    config: ClientConfig,
+    // First configured diff depth stream (e.g. "depth@100ms"); its books are keyed by symbol alone
+    primary_depth_stream: Option<String>,
+    // Stream name every frame belongs to on a raw /ws/ connection
+    raw_stream: Option<String>,
+    // Created once with the cache, so subscribers stay attached across reconnects
+    event_tx: broadcast::Sender<MarketEvent>,
+    // When set, incoming frames don't update the cache (the socket stays up).
+    // Sessions watch it to replay what they held back as soon as it clears.
+    paused: tokio::sync::watch::Sender<bool>,
+    // Symbols whose every incoming message is logged, settable at runtime
+    debug_symbols: dashmap::DashSet<String>,
+    // Lowercased firehose_symbols; empty allows every symbol
+    firehose_allowlist: std::collections::HashSet<String>,
+    // Failures counted toward MAX_RECONNECT_ATTEMPTS since the last stable connection
+    reconnect_attempts: AtomicU8,
+    // Every reconnect since startup, including ones not counted toward the limit
+    total_reconnects: AtomicU64,
+    // Text frames and their bytes received since startup, counted before any processing
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+    ingress_rate: Mutex<IngressRate>,
+    // Per book: top_changes at the last ingress sample and the best-price changes
+    // per second since the sample before it. Books that go idle are dropped.
+    flicker: DashMap<String, (u64, f64)>,
+    // Per book: accepted and rejected update counts at the last ingress sample,
+    // and the fraction accepted since the sample before it (None if no updates)
+    acceptance: DashMap<String, (u64, u64, Option<f64>)>,
+    // Recent transitions out of sync per primary depth book, within
+    // depth_downgrade_window, and the symbols downgraded to PARTIAL_DEPTH_STREAM
+    depth_desyncs: DashMap<String, VecDeque<tokio::time::Instant>>,
+    // When each book was last refetched for diverging from its bookTicker
+    divergence_refetches: DashMap<String, tokio::time::Instant>,
+    depth_downgraded: DashSet<String>,
+    // Per symbol: the previous and the current next-funding time seen on its mark
+    // price, and the latest funding-aligned refresh time already acted on (unix ms)
+    funding_refresh: DashMap<String, (u64, u64, u64)>,
+    // Frames per unknown or unhandled stream type (name without symbol or speed), since startup
+    unknown_streams: DashMap<String, u64>,
+    // Text frames that weren't a parseable stream message (e.g. truncated), since startup
+    invalid_frames: AtomicU64,
+    // Trades dropped by dedup_trades, since startup
+    duplicate_trades: AtomicU64,
+    // Books that have been synced at least once, for quiet_start
+    ever_synced: DashSet<String>,
+    // Set once quiet_start's bound has passed; every book logs normally from then
+    quiet_start_over: AtomicBool,
+    // Pending force_reconnect request (whether to preserve synced books); each
+    // session watches it from its start, so only requests made during that
+    // session end it
+    reconnect_request: tokio::sync::watch::Sender<Option<bool>>,
+    // Book keys waiting for an out-of-band snapshot refetch, and the wakeup for
+    // the task that fetches them
+    snapshot_refresh: Mutex<std::collections::HashMap<String, &'static str>>,
+    snapshot_refresh_notify: tokio::sync::Notify,
+    // Depth updates received since each pending refetch was requested, replayed
+    // on top of the snapshot it installs. Written under the book's own guard.
+    refresh_buffers: DashMap<String, VecDeque<DepthUpdateData>>,
+    // Books restored from a fresh checkpoint, skipped by the startup snapshot
+    // fetch until their first update shows whether the stream continues them
+    restored_books: DashSet<String>,
+}
+
+impl MarketDataCache {
+    fn new(config: &ClientConfig) -> Self {
+        Self::with_store(config, Arc::new(MarketStore::new(config.cache_shards)))
+    }
+
+    fn with_store(config: &ClientConfig, store: Arc<MarketStore>) -> Self {
+        store.markets.insert(config.market);
+        Self {
+            store,
+            unarchived_trades: Mutex::new(Vec::new()),
+            inter_arrival: DashMap::new(),
+            resync_durations: DashMap::new(),
+            sync_retries: DashMap::new(),
+            checked_update_ids: DashMap::new(),
+            empty_side_since: DashMap::new(),
+            notional_history: DashMap::new(),
+            spread_history: DashMap::new(),
+            ofi_history: DashMap::new(),
+            events: EventLog::new(config.event_log_capacity),
+            config: config.clone(),
+            primary_depth_stream: config.depth_streams().next().map(str::to_owned),
+            raw_stream: config.raw_stream_name(),
+            event_tx: broadcast::channel(config.broadcast_capacity).0,
+            paused: tokio::sync::watch::Sender::new(false),
+            debug_symbols: config.debug_symbols.iter().map(|s| s.to_uppercase()).collect(),
+            firehose_allowlist: config.firehose_symbols.iter().map(|s| s.to_lowercase()).collect(),
+            reconnect_attempts: AtomicU8::new(0),
+            total_reconnects: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            ingress_rate: Mutex::new(IngressRate::default()),
+            flicker: DashMap::new(),
+            acceptance: DashMap::new(),
+            depth_desyncs: DashMap::new(),
+            divergence_refetches: DashMap::new(),
+            depth_downgraded: DashSet::new(),
+            funding_refresh: DashMap::new(),
+            unknown_streams: DashMap::new(),
+            invalid_frames: AtomicU64::new(0),
+            duplicate_trades: AtomicU64::new(0),
+            ever_synced: DashSet::new(),
+            quiet_start_over: AtomicBool::new(false),
+            reconnect_request: tokio::sync::watch::Sender::new(None),
+            snapshot_refresh: Mutex::new(std::collections::HashMap::new()),
+            snapshot_refresh_notify: tokio::sync::Notify::new(),
+            refresh_buffers: DashMap::new(),
+            restored_books: DashSet::new(),
+        }
+    }
+
+    // Store key of one of this cache's own symbols or book keys
+    fn key(&self, key: &str) -> MarketKey {
+        (self.config.market, key.to_owned())
+    }
+
+    fn resolve(&self, symbol: MarketSymbol<'_>) -> MarketKey {
+        (symbol.market.unwrap_or(self.config.market), symbol.symbol.to_uppercase())
+    }
+
+    // Whether a store entry belongs to this cache's market
+    fn owns(&self, key: &MarketKey) -> bool {
+        key.0 == self.config.market
+    }
+
+    fn market_store(&self) -> Arc<MarketStore> {
+        Arc::clone(&self.store)
+    }
+
+    // Stop applying incoming data to the cache without disconnecting
+    fn pause(&self) {
+        if !self.paused.send_replace(true) {
+            info!("Processing paused");
+        }
+    }
+
+    fn resume(&self) {
+        if self.paused.send_replace(false) {
+            info!("Processing resumed");
+        }
+    }
+
+    // Log every message for a symbol at info level, without raising the global
+    // log level and drowning in other symbols
+    fn enable_symbol_debug(&self, symbol: &str) {
+        if self.debug_symbols.insert(symbol.to_uppercase()) {
+            info!("Per-message logging enabled for {}", symbol.to_uppercase());
+        }
+    }
+
+    fn disable_symbol_debug(&self, symbol: &str) {
+        if self.debug_symbols.remove(&symbol.to_uppercase()).is_some() {
+            info!("Per-message logging disabled for {}", symbol.to_uppercase());
+        }
+    }
+
+    // `symbol` is lowercase, as in stream names
+    fn is_firehose_allowed(&self, symbol: &str) -> bool {
+        self.firehose_allowlist.is_empty() || self.firehose_allowlist.contains(symbol)
+    }
+
+    // Count a frame of an unknown or unhandled stream type and react per
+    // unknown_stream_policy. `kind` is "Unknown" or "Unhandled" for the log.
+    fn note_unknown_stream(&self, kind: &str, stream_name: &str) -> Result<(), WebSocketError> {
+        let name = stream_name.split('@').next().unwrap_or(stream_name);
+        let count = *self.unknown_streams.entry(name.to_string()).and_modify(|c| *c += 1).or_insert(1);
+        match self.config.unknown_stream_policy {
+            UnknownStreamPolicy::Warn => warn!("{} stream type: {}", kind, stream_name),
+            UnknownStreamPolicy::WarnOnce if count == 1 => {
+                warn!("{} stream type: {} (further frames are only counted)", kind, stream_name);
+            }
+            UnknownStreamPolicy::WarnOnce | UnknownStreamPolicy::Count => {}
+            UnknownStreamPolicy::Error => return Err(WebSocketError::UnknownStream(stream_name.to_string())),
+        }
+        Ok(())
+    }
+
+    fn unknown_stream_counts(&self) -> Vec<(String, u64)> {
+        self.unknown_streams.iter().map(|r| (r.key().clone(), *r.value())).collect()
+    }
+
+    fn is_symbol_debug(&self, symbol: &str) -> bool {
+        // Skip the uppercase allocation on the hot path in the common case
+        !self.debug_symbols.is_empty() && self.debug_symbols.contains(&symbol.to_uppercase())
+    }
+
+    // Close the current connection and go through the reconnect path. With
+    // preserve_books, books that are still synced skip the snapshot refetch and
+    // carry on if the new stream follows on from them.
+    fn force_reconnect(&self, preserve_books: bool) {
+        self.reconnect_request.send_replace(Some(preserve_books));
+    }
+
+    fn take_reconnect_request(&self) -> Option<bool> {
+        self.reconnect_request.send_replace(None)
+    }
+
+    // Queue a snapshot refetch for a book; `reason` ends up in the event log
+    fn request_snapshot_refresh(&self, key: &str, reason: &'static str) {
+        // Partial depth books resync from their next frame
+        if self.is_depth_downgraded(key) {
+            return;
+        }
+        self.begin_snapshot_refresh(key);
+        self.snapshot_refresh.lock().unwrap_or_else(|e| e.into_inner()).insert(key.to_string(), reason);
+        self.snapshot_refresh_notify.notify_one();
+    }
+
+    // Refetch every depth book, e.g. after frames were dropped unseen
+    fn request_snapshot_refresh_all(&self, reason: &'static str) {
+        let keys: Vec<String> = self.store.order_books
+            .iter()
+            .filter(|book| self.owns(book.key()))
+            .map(|book| book.key().1.clone())
+            .collect();
+        for key in keys {
+            self.request_snapshot_refresh(&key, reason);
+        }
+    }
+
+    // Start buffering the book's updates before its refetch goes out, so the ones
+    // the snapshot doesn't cover yet can be replayed on top of it
+    fn begin_snapshot_refresh(&self, key: &str) {
+        self.refresh_buffers.entry(key.to_string()).or_default();
+    }
+
+    // Keep an update for the book's pending refetch, if there is one. Called
+    // with the book's guard held; see install_refreshed_snapshot.
+    fn buffer_for_refresh(&self, key: &str, update: &DepthUpdateData) {
+        if let Some(mut buffer) = self.refresh_buffers.get_mut(key) {
+            if buffer.len() >= REFRESH_BUFFER_CAPACITY {
+                buffer.pop_front();
+            }
+            buffer.push_back(update.clone());
+        }
+    }
+
+    // Install a snapshot refetched for request_snapshot_refresh, then replay the
+    // updates buffered since the request. The stream isn't paused during the
+    // fetch, so the snapshot is usually behind it; without the replay the next
+    // live update couldn't bridge it and the book would stay unsynced.
+    fn install_refreshed_snapshot(
+        &self,
+        key: &str,
+        snapshot: OrderBookSnapshot,
+    ) -> dashmap::mapref::one::RefMut<'_, MarketKey, OrderBook> {
+        let mut book = self.install_snapshot(key, snapshot);
+        let key = book.key().1.clone();
+        // Taken under the same guard update_order_book buffers under, so every
+        // update is either in this buffer or applied after the install
+        let buffered = self.refresh_buffers.remove(&key).map(|(_, b)| b).unwrap_or_default();
+        let snapshot_id = book.last_update_id;
+        for update in buffered.iter().filter(|u| u.final_update_id >= snapshot_id) {
+            if let Err(e) = book.apply_update(update) {
+                warn!("Order book {} needs resyncing after snapshot refetch: {}", key, e);
+                break;
+            }
+        }
+        if book.synced {
+            if let Some(desynced_at) = book.desynced_at.take() {
+                self.record_resync_duration(&key, desynced_at.elapsed());
+            }
+            self.note_synced(&key);
+            self.publish_full_book(&book);
+        }
+        book
+    }
+
+    // Drop the buffer of a refetch that failed
+    fn abandon_snapshot_refresh(&self, key: &str) {
+        self.refresh_buffers.remove(key);
+    }
+
+    // Broadcast a whole book, as a snapshot event in delta mode
+    fn publish_full_book(&self, book: &OrderBook) {
+        if self.event_tx.receiver_count() == 0 {
+            return;
+        }
+        let event = match self.config.book_broadcast {
+            BookBroadcastMode::Snapshot => {
+                MarketEvent::OrderBookUpdate(OrderBookUpdateEvent::from_book(book, self.config.broadcast_depth))
+            }
+            BookBroadcastMode::Delta => MarketEvent::OrderBookSnapshot(OrderBookUpdateEvent::from_book(book, usize::MAX)),
+        };
+        self.publish(event);
+    }
+
+    fn take_snapshot_refresh(&self) -> Vec<(String, &'static str)> {
+        self.snapshot_refresh.lock().unwrap_or_else(|e| e.into_inner()).drain().collect()
+    }
+
+    fn reconnect_attempts(&self) -> u8 {
+        self.reconnect_attempts.load(AtomicOrdering::Relaxed)
+    }
+
+    fn total_reconnects(&self) -> u64 {
+        self.total_reconnects.load(AtomicOrdering::Relaxed)
+    }
+
+    fn record_ingress(&self, bytes: usize) {
+        self.messages_received.fetch_add(1, AtomicOrdering::Relaxed);
+        self.bytes_received.fetch_add(bytes as u64, AtomicOrdering::Relaxed);
+    }
+
+    fn invalid_frames(&self) -> u64 {
+        self.invalid_frames.load(AtomicOrdering::Relaxed)
+    }
+
+    fn duplicate_trades(&self) -> u64 {
+        self.duplicate_trades.load(AtomicOrdering::Relaxed)
+    }
+
+    // A frame that failed to parse may have carried a depth update, so the
+    // books it could belong to are resynced rather than left to drift: the one
+    // named by the frame's stream if that much survived, else every book.
+    fn record_invalid_frame(&self, text: &str, error: &serde_json::Error) {
+        self.invalid_frames.fetch_add(1, AtomicOrdering::Relaxed);
+        let snippet: String = text.chars().take(INVALID_FRAME_SNIPPET_CHARS).collect();
+        warn!("Invalid frame ({} bytes): {}: {:?}", text.len(), error, snippet);
+        self.events.record(EventKind::ParseError, None, format!("invalid frame ({} bytes): {}", text.len(), error));
+
+        if self.primary_depth_stream.is_none() {
+            return;
+        }
+        let stream = self.raw_stream.as_deref().or_else(|| frame_stream_name(text));
+        match stream.and_then(|stream| stream.split_once('@')) {
+            Some((symbol, stream_name)) => {
+                if stream_name.parse() == Ok(StreamType::Depth) {
+                    self.invalidate_book(&self.order_book_key(symbol, stream_name), "invalid frame");
+                }
+            }
+            // Firehose streams never carry depth
+            None if stream.is_some_and(|stream| stream.starts_with('!')) => {}
+            None => {
+                let keys: Vec<String> = self.store.order_books
+                    .iter()
+                    .filter(|r| self.owns(r.key()))
+                    .map(|r| r.key().1.clone())
+                    .collect();
+                for key in keys {
+                    self.invalidate_book(&key, "invalid frame");
+                }
+            }
+        }
+    }
+
+    // Mark a book unsynced after it may have missed an update, and refetch its snapshot
+    fn invalidate_book(&self, key: &str, reason: &'static str) {
+        let Some(mut book) = self.store.order_books.get_mut(&self.key(key)) else {
+            return;
+        };
+        if book.synced {
+            warn!("Order book {} needs resyncing: {}", key, reason);
+            book.synced = false;
+            book.desynced_at = Some(tokio::time::Instant::now());
+            self.events.record(EventKind::Resync, Some(key), reason.to_string());
+        }
+        drop(book);
+        self.request_snapshot_refresh(key, reason);
+    }
+
+    // (messages, bytes) received since startup
+    fn ingress_totals(&self) -> (u64, u64) {
+        (
+            self.messages_received.load(AtomicOrdering::Relaxed),
+            self.bytes_received.load(AtomicOrdering::Relaxed),
+        )
+    }
+
+    // Recompute the rates from the totals at the previous sample, which is updated in place
+    fn sample_ingress(&self, previous: &mut (u64, u64), elapsed: Duration) {
+        let totals = self.ingress_totals();
+        let secs = elapsed.as_secs_f64();
+        if secs > 0.0 {
+            *self.ingress_rate.lock().unwrap_or_else(|e| e.into_inner()) = IngressRate {
+                messages_per_sec: (totals.0 - previous.0) as f64 / secs,
+                bytes_per_sec: (totals.1 - previous.1) as f64 / secs,
+            };
+        }
+        *previous = totals;
+    }
+
+    fn ingress_rate(&self) -> IngressRate {
+        *self.ingress_rate.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Recompute each book's best-price change rate, warning when one rises
+    // above flicker_alert_rate. Books idle for FLICKER_IDLE_SECS are no longer
+    // tracked, and start from a fresh baseline if they come back.
+    fn sample_flicker(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return;
+        }
+        let alert_rate = self.config.flicker_alert_rate;
+        let idle_after = Duration::from_secs(FLICKER_IDLE_SECS);
+        for entry in self.store.order_books.iter().filter(|e| self.owns(e.key())) {
+            let key = &entry.key().1;
+            if entry.value().updated_at.is_none_or(|at| at.elapsed() >= idle_after) {
+                self.flicker.remove(key);
+                continue;
+            }
+            let changes = entry.value().top_changes;
+            let mut sample = self.flicker.entry(key.clone()).or_insert((changes, 0.0));
+            let (previous, previous_rate) = *sample;
+            let rate = changes.saturating_sub(previous) as f64 / secs;
+            *sample = (changes, rate);
+            drop(sample);
+            if alert_rate.is_some_and(|limit| rate > limit && previous_rate <= limit) {
+                warn!("Order book {} is flickering: best price changed {:.0} times/s", key, rate);
+                self.events.record(EventKind::Flicker, Some(key), format!("{:.0} best-price changes/s", rate));
+            }
+        }
+    }
+
+    // Recompute each book's depth update acceptance rate, warning when one
+    // falls below acceptance_alert_rate
+    fn sample_acceptance(&self) {
+        let alert_rate = self.config.acceptance_alert_rate;
+        for entry in self.store.order_books.iter().filter(|e| self.owns(e.key())) {
+            let key = &entry.key().1;
+            let (accepted, rejected) = (entry.value().accepted_updates, entry.value().rejected_updates);
+            let mut sample = self.acceptance.entry(key.clone()).or_insert((accepted, rejected, None));
+            let (previous_accepted, previous_rejected, previous_rate) = *sample;
+            let (new_accepted, new_rejected) = (accepted.saturating_sub(previous_accepted), rejected.saturating_sub(previous_rejected));
+            let rate = match new_accepted + new_rejected {
+                0 => None,
+                total => Some(new_accepted as f64 / total as f64),
+            };
+            *sample = (accepted, rejected, rate);
+            drop(sample);
+            let below = |rate: Option<f64>| alert_rate.is_some_and(|limit| rate.is_some_and(|r| r < limit));
+            if below(rate) && !below(previous_rate) {
+                let rate = rate.unwrap_or_default();
+                warn!(
+                    "Order book {} accepted {:.1}% of depth updates ({} of {})",
+                    key, rate * 100.0, new_accepted, new_accepted + new_rejected
+                );
+                self.events.record(
+                    EventKind::LowAcceptance,
+                    Some(key),
+                    format!("{:.1}% of depth updates accepted", rate * 100.0),
+                );
+            }
+        }
+    }
+
+    // Fraction of depth updates accepted over the last ingress sample interval;
+    // None until sampled or if the book received no updates in it
+    fn acceptance_rate(&self, symbol: &str) -> Option<f64> {
+        self.acceptance.get(&symbol.to_uppercase()).and_then(|sample| sample.2)
+    }
+
+    // Depth updates accepted and rejected since startup
+    fn update_counts<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<(u64, u64)> {
+        let book = self.store.order_books.get(&self.resolve(symbol.into()))?;
+        Some((book.accepted_updates, book.rejected_updates))
+    }
+
+    // Best bid or ask price changes per second over the last ingress sample interval
+    fn flicker_rate(&self, symbol: &str) -> Option<f64> {
+        self.flicker.get(&symbol.to_uppercase()).map(|sample| sample.1)
+    }
+
+    // Books whose last sampled flicker rate is above `rate`, fastest first
+    fn flickering_books(&self, rate: f64) -> Vec<(String, f64)> {
+        let mut books: Vec<(String, f64)> = self.flicker
+            .iter()
+            .filter(|sample| sample.1 > rate)
+            .map(|sample| (sample.key().clone(), sample.1))
+            .collect();
+        books.sort_by(|a, b| b.1.total_cmp(&a.1));
+        books
+    }
+
+    // Cache key for a symbol's book maintained from the given depth stream.
+    // The primary depth stream keys by symbol alone so the usual lookups work;
+    // other speeds of the same symbol get a separate book under SYMBOL@STREAM.
+    fn order_book_key(&self, symbol: &str, depth_stream: &str) -> String {
+        if self.primary_depth_stream.as_deref() == Some(depth_stream) {
+            symbol.to_uppercase()
+        } else {
+            format!("{}@{}", symbol, depth_stream).to_uppercase()
+        }
+    }
+
+    fn touch(&self, key: &MarketKey, stream_type: StreamType) {
+        if self.config.stale_after.is_some() {
+            self.store.last_updated.insert((key.clone(), stream_type), tokio::time::Instant::now());
+        }
+    }
+
+    // Dead-man's switch: true when stale_after is configured and the symbol's
+    // data of this type hasn't been updated within it (or ever). Stale data is
+    // hidden from reads so a silently stalled feed can't serve frozen prices.
+    // Data of other markets is judged by this cache's stale_after too.
+    fn is_stale(&self, key: &MarketKey, stream_type: StreamType) -> bool {
+        let Some(stale_after) = self.config.stale_after else {
+            return false;
+        };
+        self.store.last_updated
+            .get(&(key.clone(), stream_type))
+            .is_none_or(|at| at.elapsed() > stale_after)
+    }
+
+    fn book_ticker<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<BookTickerData> {
+        let key = self.resolve(symbol.into());
+        if self.is_stale(&key, StreamType::BookTicker) {
+            return None;
+        }
+        self.store.book_tickers.get(&key).map(|r| r.value().clone())
+    }
+
+    fn last_trade<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<TradeData> {
+        let key = self.resolve(symbol.into());
+        if self.is_stale(&key, StreamType::Trade) {
+            return None;
+        }
+        self.store.last_trades.get(&key).map(|r| r.value().clone())
+    }
+
+    fn mark_price<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<MarkPriceData> {
+        let key = self.resolve(symbol.into());
+        if self.is_stale(&key, StreamType::MarkPrice) {
+            return None;
+        }
+        self.store.mark_prices.get(&key).map(|r| r.value().clone())
+    }
+
+    // Run a closure against a book under its read guard, without cloning it.
+    // Keep the closure short: it blocks writers to the same shard.
+    fn with_order_book<'a, R>(&self, symbol: impl Into<MarketSymbol<'a>>, f: impl FnOnce(&OrderBook) -> R) -> Option<R> {
+        let key = self.resolve(symbol.into());
+        if self.is_stale(&key, StreamType::Depth) {
+            return None;
+        }
+        self.store.order_books.get(&key).map(|book| f(book.value()))
+    }
+
+    // Top `levels` of both sides, the best bid/ask and the last trade in one
+    // call. The depth fields all come from one book state, and the last trade
+    // is read while that book's read guard is still held, so no depth update
+    // for the symbol lands between the two reads. Trades and depth arrive on
+    // separate streams with no shared sequence, though, so the trade can still
+    // be slightly ahead of or behind the book. None if the book is missing or
+    // stale, as with_order_book.
+    fn market_state<'a>(&self, symbol: impl Into<MarketSymbol<'a>>, levels: usize) -> Option<MarketState> {
+        let key = self.resolve(symbol.into());
+        if self.is_stale(&key, StreamType::Depth) {
+            return None;
+        }
+        let trade_stale = self.is_stale(&key, StreamType::Trade);
+        // Nested guard on a different map: trade writers never touch order_books
+        // while holding last_trades, so this can't deadlock
+        let book = self.store.order_books.get(&key)?;
+        let last_trade = if trade_stale {
+            None
+        } else {
+            self.store.last_trades.get(&key).map(|r| r.value().clone())
+        };
+        Some(MarketState {
+            bids: book.top_bids(levels),
+            asks: book.top_asks(levels),
+            top: book.top_of_book(),
+            last_update_id: book.last_update_id,
+            synced: book.synced,
+            last_trade,
+        })
+    }
+
+    // Best bid and ask per quote_source; stale data is hidden as elsewhere
+    fn top_of_book<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<TopOfBook> {
+        let symbol = symbol.into();
+        let book_top = || self.with_order_book(symbol, OrderBook::top_of_book).flatten();
+        match self.config.quote_source {
+            QuoteSource::Book => book_top(),
+            QuoteSource::BookTicker => self.ticker_top_of_book(symbol),
+            QuoteSource::PreferBook => {
+                let synced = self.store.order_books.get(&self.resolve(symbol)).is_some_and(|book| book.synced);
+                synced.then(book_top).flatten().or_else(|| self.ticker_top_of_book(symbol))
+            }
+        }
+    }
+
+    fn ticker_top_of_book<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<TopOfBook> {
+        let key = self.resolve(symbol.into());
+        if self.is_stale(&key, StreamType::BookTicker) {
+            return None;
+        }
+        self.store.book_tickers.get(&key).map(|ticker| ticker.top_of_book())
+    }
+
+    // Best prices plus sync state and age, read per quote_source. Unlike the
+    // other readers this still returns stale data, flagged, so callers can
+    // decide what to do. Under PreferBook an unsynced or stale book is only
+    // returned when there is no bookTicker to fall back to.
+    fn quote<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<Quote> {
+        let key = self.resolve(symbol.into());
+        match self.config.quote_source {
+            QuoteSource::Book => self.book_quote(&key),
+            QuoteSource::BookTicker => self.ticker_quote(&key),
+            QuoteSource::PreferBook => match self.book_quote(&key) {
+                Some(quote) if quote.synced && !quote.stale => Some(quote),
+                book => self.ticker_quote(&key).or(book),
+            },
+        }
+    }
+
+    // Depth book quote, read under a single guard so it can't be observed mid-update
+    fn book_quote(&self, key: &MarketKey) -> Option<Quote> {
+        let book = self.store.order_books.get(key)?;
+        let age = book.updated_at.map(|at| at.elapsed());
+        Some(Quote {
+            top: book.top_of_book()?,
+            from_book_ticker: false,
+            synced: book.synced,
+            age,
+            stale: self.config.stale_after.is_some_and(|limit| age.is_none_or(|age| age > limit)),
+        })
+    }
+
+    fn ticker_quote(&self, key: &MarketKey) -> Option<Quote> {
+        let ticker = self.store.book_tickers.get(key)?;
+        let age = self.store.last_updated
+            .get(&(key.clone(), StreamType::BookTicker))
+            .map(|at| at.elapsed());
+        Some(Quote {
+            top: ticker.top_of_book(),
+            from_book_ticker: true,
+            synced: true,
+            age,
+            stale: self.is_stale(key, StreamType::BookTicker),
+        })
+    }
+
+    // Buffered trades for a symbol, oldest first
+    fn recent_trades<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Vec<TradeData> {
+        self.store.recent_trades
+            .get(&self.resolve(symbol.into()))
+            .map(|r| r.value().iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Buffered trades with their quotes, oldest first; empty unless trade_quote_source is set
+    fn quoted_trades<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Vec<QuotedTrade> {
+        self.store.quoted_trades
+            .get(&self.resolve(symbol.into()))
+            .map(|r| r.value().iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Best bid and ask from `source` right now, without staleness filtering
+    fn prevailing_quote(&self, key: &MarketKey, source: TradeQuoteSource) -> Option<(Decimal, Decimal)> {
+        match source {
+            TradeQuoteSource::Book => {
+                let book = self.store.order_books.get(key)?;
+                if !book.synced {
+                    return None;
+                }
+                book.top_of_book().map(|top| (top.bid_price, top.ask_price))
+            }
+            TradeQuoteSource::BookTicker => {
+                self.store.book_tickers.get(key).map(|ticker| (ticker.bid_price, ticker.ask_price))
+            }
+        }
+    }
+
+    // Sample standard deviation of log returns between consecutive buffered
+    // trades within `window` of the latest trade. Per-trade, not annualized.
+    // None with fewer than two returns in the window.
+    fn realized_volatility<'a>(&self, symbol: impl Into<MarketSymbol<'a>>, window: Duration) -> Option<f64> {
+        let buffer = self.store.recent_trades.get(&self.resolve(symbol.into()))?;
+        let latest = buffer.back()?.trade_time;
+        let since = latest.saturating_sub(window.as_millis() as u64);
+        let prices: Vec<f64> = buffer
+            .iter()
+            .filter(|t| t.trade_time >= since)
+            .filter_map(|t| t.price.to_f64())
+            .filter(|p| *p > 0.0)
+            .collect();
+        let returns: Vec<f64> = prices.windows(2).map(|w| (w[1] / w[0]).ln()).collect();
+        if returns.len() < 2 {
+            return None;
+        }
+        let n = returns.len() as f64;
+        let mean = returns.iter().sum::<f64>() / n;
+        let variance = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        Some(variance.sqrt())
+    }
+
+    // Current and peak (bids + asks) level counts of a book
+    fn book_levels<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<(usize, usize)> {
+        let book = self.store.order_books.get(&self.resolve(symbol.into()))?;
+        Some((book.level_count(), book.peak_levels))
+    }
+
+    // Time since the book last accepted a stream update. A book that keeps
+    // receiving but rejecting updates ages here even though messages flow.
+    fn book_age<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<Duration> {
+        self.store.order_books
+            .get(&self.resolve(symbol.into()))?
+            .last_accepted_at
+            .map(|at| at.elapsed())
+    }
+
+    // Book spread expressed in whole ticks of the symbol's tick size
+    fn spread_in_ticks<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<u64> {
+        let symbol = symbol.into();
+        let tick_size = self.store.symbol_info.get(&self.resolve(symbol))?.tick_size?;
+        if tick_size.is_zero() {
+            return None;
+        }
+        let spread = self.with_order_book(symbol, OrderBook::spread)??;
+        (spread / tick_size).round().to_u64()
+    }
+
+    // Whether the spread is wider than `bps` basis points of the mid, with both
+    // sides read under one guard. None if the book is missing, stale, unsynced
+    // or one-sided.
+    fn spread_exceeds<'a>(&self, symbol: impl Into<MarketSymbol<'a>>, bps: Decimal) -> Option<bool> {
+        self.with_order_book(symbol, |book| {
+            if !book.synced {
+                return None;
+            }
+            let top = book.top_of_book()?;
+            let mid = (top.bid_price + top.ask_price) / Decimal::TWO;
+            if mid <= Decimal::ZERO {
+                return None;
+            }
+            Some((top.ask_price - top.bid_price) / mid * Decimal::from(10_000) > bps)
+        })
+        .flatten()
+    }
+
+    // Log prices in a depth update that are off the symbol's tick grid. Skipped
+    // when exchangeInfo has no tick size for the symbol.
+    fn check_tick_size(&self, key: &str, update: &DepthUpdateData) {
+        let symbol = key.split('@').next().unwrap_or(key);
+        let Some(tick_size) = self.store.symbol_info.get(&self.key(symbol)).and_then(|info| info.tick_size) else {
+            return;
+        };
+        if tick_size.is_zero() {
+            return;
+        }
+        let off_tick: Vec<Decimal> = update.bids.iter()
+            .chain(&update.asks)
+            .filter_map(|level| Decimal::from_str_exact(&level[0]).ok())
+            .filter(|price| !(price % tick_size).is_zero())
+            .collect();
+        if let Some(first) = off_tick.first() {
+            warn!(
+                "{} update u={} has {} price(s) off the {} tick, e.g. {}",
+                key, update.final_update_id, off_tick.len(), tick_size, first
+            );
+        }
+    }
+
+    fn record_arrival(&self, stream: &str) {
+        let now = tokio::time::Instant::now();
+        if let Some(mut tracker) = self.inter_arrival.get_mut(stream) {
+            tracker.record(now);
+            return;
+        }
+        self.inter_arrival
+            .entry(stream.to_owned())
+            .or_insert_with(InterArrivalTracker::new)
+            .record(now);
+    }
+
+    // Inter-arrival statistics per stream name, sorted by stream
+    fn inter_arrival_stats(&self) -> Vec<(String, InterArrivalStats)> {
+        let mut stats: Vec<_> = self.inter_arrival
+            .iter()
+            .filter_map(|r| Some((r.key().clone(), r.value().stats()?)))
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+
+    // Subscribe to market events; lagging receivers drop the oldest events
+    fn subscribe(&self) -> broadcast::Receiver<MarketEvent> {
+        self.event_tx.subscribe()
+    }
+
+    fn publish(&self, event: MarketEvent) {
+        // An error only means there are no subscribers right now
+        let _ = self.event_tx.send(event);
+    }
+
+    // Publish every cached bookTicker as stale, bridging the gap until fresh ones arrive
+    fn replay_book_tickers(&self) -> usize {
+        if self.event_tx.receiver_count() == 0 {
+            return 0;
+        }
+        let tickers: Vec<BookTickerData> = self.store.book_tickers
+            .iter()
+            .filter(|r| self.owns(r.key()))
+            .map(|r| r.value().clone())
+            .collect();
+        let count = tickers.len();
+        for ticker in tickers {
+            self.publish(MarketEvent::StaleBookTicker(ticker));
+        }
+        count
+    }
+
+    // Last N operational events, oldest first
+    fn recent_events(&self) -> Vec<OperationalEvent> {
+        self.events.snapshot()
+    }
+
+    fn batch_update_book_tickers(&self, tickers: Vec<BookTickerData>) {
+        for ticker in tickers {
+            let symbol = ticker.symbol.to_uppercase();
+            let key = self.key(&symbol);
+            // Keep the cache monotonic in update id across reconnects
+            match self.store.book_ticker_high_water.entry(key.clone()) {
+                dashmap::mapref::entry::Entry::Occupied(mut entry) => {
+                    if ticker.update_id < *entry.get() {
+                        debug!("Discarding stale book ticker for {} (u={} < {})", symbol, ticker.update_id, entry.get());
+                        continue;
+                    }
+                    entry.insert(ticker.update_id);
+                }
+                dashmap::mapref::entry::Entry::Vacant(entry) => {
+                    entry.insert(ticker.update_id);
+                }
+            }
+            if let Some(tolerance) = self.config.cross_check_tolerance_bps {
+                self.cross_check_ticker(&symbol, &ticker, tolerance);
+            }
+            self.touch(&key, StreamType::BookTicker);
+            if self.event_tx.receiver_count() > 0 {
+                self.publish(MarketEvent::BookTicker(ticker.clone()));
+            }
+            self.store.book_tickers.insert(key, ticker);
+        }
+    }
+
+    // Compare the bookTicker top of book against the synced depth book and flag drift
+    fn cross_check_ticker(&self, symbol: &str, ticker: &BookTickerData, tolerance_bps: Decimal) {
+        let Some(mut book) = self.store.order_books.get_mut(&self.key(symbol)) else {
+            return;
+        };
+        if !book.synced {
+            return;
+        }
+        let (Some((book_bid, _)), Some((book_ask, _))) = (book.best_bid(), book.best_ask()) else {
+            return;
+        };
+
+        let bps = |a: Decimal, b: Decimal| {
+            if b.is_zero() {
+                Decimal::ZERO
+            } else {
+                ((a - b).abs() / b) * Decimal::from(10_000)
+            }
+        };
+        let divergence = bps(ticker.bid_price, book_bid).max(bps(ticker.ask_price, book_ask));
+
+        if divergence > tolerance_bps {
+            warn!(
+                "{} bookTicker diverges from depth book by {} bps: ticker {}/{} vs book {}/{}",
+                symbol, divergence.round_dp(2), ticker.bid_price, ticker.ask_price, book_bid, book_ask
+            );
+            self.events.record(
+                EventKind::Divergence,
+                Some(symbol),
+                format!("{} bps (ticker u={}, book u={})", divergence.round_dp(2), ticker.update_id, book.last_update_id),
+            );
+            book.ticker_divergence_bps = Some(divergence);
+            // A divergence that outlives the refetch would otherwise refetch on every ticker
+            let action = match self.config.divergence_action {
+                DivergenceAction::Snapshot if !self.divergence_refetch_due(symbol) => {
+                    debug!("Order book {} refetched for divergence within the cooldown, not refetching", symbol);
+                    DivergenceAction::Log
+                }
+                action => action,
+            };
+            if action != DivergenceAction::Log {
+                warn!("Order book {} needs resyncing: diverges from bookTicker", symbol);
+                book.synced = false;
+                book.desynced_at = Some(tokio::time::Instant::now());
+            }
+            if action == DivergenceAction::Snapshot {
+                self.request_snapshot_refresh(symbol, "bookTicker divergence");
+            }
+        } else {
+            book.ticker_divergence_bps = None;
+        }
+    }
+
+    // Whether a divergence may refetch the book again, noting the refetch if so
+    fn divergence_refetch_due(&self, key: &str) -> bool {
+        let cooldown = Duration::from_secs(DIVERGENCE_REFETCH_COOLDOWN_SECS);
+        if self.divergence_refetches.get(key).is_some_and(|at| at.elapsed() < cooldown) {
+            return false;
+        }
+        self.divergence_refetches.insert(key.to_owned(), tokio::time::Instant::now());
+        true
+    }
+
+    // Mark prices arrive at most once a second per symbol, so they skip batching
+    fn update_mark_price(&self, mark_price: MarkPriceData) {
+        let key = self.key(&mark_price.symbol.to_uppercase());
+        self.touch(&key, StreamType::MarkPrice);
+        self.store.mark_prices.insert(key, mark_price);
+    }
+
+    fn batch_update_trades(&self, trades: Vec<TradeData>) {
+        for trade in trades {
+            let symbol = trade.symbol.to_uppercase();
+            let key = self.key(&symbol);
+            // Trade ids increase per symbol, so anything at or below the last
+            // stored one was already seen (e.g. resent across a reconnect)
+            if self.config.dedup_trades
+                && self.store.last_trades.get(&key).is_some_and(|last| trade.trade_id <= last.trade_id)
+            {
+                debug!("Dropping duplicate trade {} for {}", trade.trade_id, symbol);
+                self.duplicate_trades.fetch_add(1, AtomicOrdering::Relaxed);
+                continue;
+            }
+            self.touch(&key, StreamType::Trade);
+            if self.event_tx.receiver_count() > 0 {
+                self.publish(MarketEvent::Trade(trade.clone()));
+            }
+            if self.config.trade_archive_dir.is_some() {
+                self.unarchived_trades.lock().unwrap_or_else(|e| e.into_inner()).push(trade.clone());
+            }
+            if let Some(source) = self.config.trade_quote_source {
+                let quote = self.prevailing_quote(&key, source);
+                let capacity = self.config.trade_buffer_size.max(1);
+                let mut buffer = self.store.quoted_trades
+                    .entry(key.clone())
+                    .or_insert_with(|| VecDeque::with_capacity(capacity));
+                if buffer.len() >= capacity {
+                    buffer.pop_front();
+                }
+                buffer.push_back(QuotedTrade {
+                    trade: trade.clone(),
+                    bid_price: quote.map(|(bid, _)| bid),
+                    ask_price: quote.map(|(_, ask)| ask),
+                });
+            }
+            let capacity = self.config.trade_buffer_size;
+            if capacity > 0 {
+                let mut buffer = self.store.recent_trades
+                    .entry(key.clone())
+                    .or_insert_with(|| VecDeque::with_capacity(capacity));
+                if buffer.len() >= capacity {
+                    buffer.pop_front();
+                }
+                buffer.push_back(trade.clone());
+            }
+            self.store.last_trades.insert(key, trade);
+        }
+    }
+
+    // Write queued trades to the archive. They stay queued until the write is
+    // on disk, so a failed write is retried with the same trades first and a
+    // crash mid-write loses nothing that was reported archived. Blocking; the
+    // archive's lock keeps concurrent callers from writing the same trades.
+    fn archive_trades(&self, archive: &Mutex<TradeArchive>) -> Result<usize, WebSocketError> {
+        let archive = archive.lock().unwrap_or_else(|e| e.into_inner());
+        let trades = self.unarchived_trades.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        if trades.is_empty() {
+            return Ok(0);
+        }
+        archive.write(&trades)?;
+        // Trades received since the clone were appended behind these
+        self.unarchived_trades.lock().unwrap_or_else(|e| e.into_inner()).drain(..trades.len());
+        Ok(trades.len())
+    }
+
+    fn checkpoint(&self) -> CacheCheckpoint {
+        CacheCheckpoint {
+            saved_at_ms: unix_time_ms(),
+            order_books: self.store.order_books
+                .iter()
+                .filter(|r| self.owns(r.key()))
+                .map(|r| r.value().clone())
+                .collect(),
+        }
+    }
+
+    // Write all order books to disk, as bincode for .bin paths and JSON otherwise
+    fn save_checkpoint(&self, path: &std::path::Path) -> Result<usize, WebSocketError> {
+        if is_bincode_path(path) {
+            return self.save_bincode(path);
+        }
+        let checkpoint = self.checkpoint();
+        write_atomically(path, &serde_json::to_vec(&checkpoint)?)?;
+        Ok(checkpoint.order_books.len())
+    }
+
+    // Compact binary checkpoint; much smaller and faster to load than JSON for deep books
+    fn save_bincode(&self, path: &std::path::Path) -> Result<usize, WebSocketError> {
+        let checkpoint = self.checkpoint();
+        write_atomically(path, &bincode::serialize(&checkpoint)?)?;
+        Ok(checkpoint.order_books.len())
+    }
+
+    fn load_checkpoint(&self, path: &std::path::Path) -> Result<usize, WebSocketError> {
+        if is_bincode_path(path) {
+            return self.load_bincode(path);
+        }
+        Ok(self.restore_checkpoint(serde_json::from_slice(&std::fs::read(path)?)?))
+    }
+
+    fn load_bincode(&self, path: &std::path::Path) -> Result<usize, WebSocketError> {
+        Ok(self.restore_checkpoint(bincode::deserialize(&std::fs::read(path)?)?))
+    }
+
+    // Restore order books from a checkpoint. Restored books are unsynced until
+    // a fresh snapshot and the stream catch up, or, from a checkpoint within
+    // checkpoint_max_age, until the stream continues where they left off.
+    fn restore_checkpoint(&self, checkpoint: CacheCheckpoint) -> usize {
+        let count = checkpoint.order_books.len();
+        let age = Duration::from_millis(unix_time_ms().saturating_sub(checkpoint.saved_at_ms));
+        let fresh = age <= self.config.checkpoint_max_age;
+        for mut book in checkpoint.order_books {
+            book.synced = false;
+            book.min_qty = self.min_level_qty(&book.symbol);
+            book.set_group_tick(self.group_tick(&book.symbol));
+            book.record_peak_levels();
+            if fresh {
+                self.restored_books.insert(book.symbol.clone());
+            }
+            self.store.order_books.insert(self.key(&book.symbol), book);
+        }
+        count
+    }
+
+    // Dust threshold for a book: an explicit per-symbol setting, else a multiple
+    // of the step size when exchangeInfo has it, else exact zero
+    fn min_level_qty(&self, key: &str) -> Decimal {
+        let symbol = key.split('@').next().unwrap_or(key).to_uppercase();
+        let configured = self.config.min_level_qty
+            .iter()
+            .find(|(s, _)| s.eq_ignore_ascii_case(&symbol))
+            .map(|(_, qty)| *qty);
+        if let Some(qty) = configured {
+            return qty;
+        }
+        self.config.min_level_qty_steps
+            .zip(self.store.symbol_info.get(&self.key(&symbol)).and_then(|info| info.step_size))
+            .map(|(steps, step_size)| steps * step_size)
+            .unwrap_or(Decimal::ZERO)
+    }
+
+    // Grouped view tick configured for a book's symbol
+    fn group_tick(&self, key: &str) -> Option<Decimal> {
+        let symbol = key.split('@').next().unwrap_or(key);
+        self.config.group_tick
+            .iter()
+            .find(|(s, _)| s.eq_ignore_ascii_case(symbol))
+            .map(|(_, tick)| *tick)
+    }
+
+    // Replace (or create) a symbol's book from a REST snapshot. The book then
+    // resyncs against the stream via the usual U/u bridging rules.
+    fn install_snapshot(
+        &self,
+        symbol: &str,
+        snapshot: OrderBookSnapshot,
+    ) -> dashmap::mapref::one::RefMut<'_, MarketKey, OrderBook> {
+        let symbol = symbol.to_uppercase();
+        let key = self.key(&symbol);
+        self.touch(&key, StreamType::Depth);
+        let mut book = self.store.order_books
+            .entry(key)
+            .or_insert_with(|| OrderBook::new(symbol.clone()));
+        book.min_qty = self.min_level_qty(&symbol);
+        book.group_tick = self.group_tick(&symbol);
+        book.apply_snapshot(snapshot);
+        self.restored_books.remove(&symbol);
+        book
+    }
+
+    // Append each synced book's current top-N notional to its rolling history
+    fn sample_notional(&self) {
+        let (levels, window) = (self.config.thinness_levels, self.config.thinness_window);
+        // Read every book first, so no order_books guard is held while the
+        // history is written (is_thin takes them in the other order)
+        let samples: Vec<(String, Decimal)> = self.store.order_books
+            .iter()
+            .filter(|e| self.owns(e.key()) && e.value().synced)
+            .map(|e| (e.key().1.clone(), e.value().notional(levels)))
+            .collect();
+        for (key, notional) in samples {
+            let mut history = self.notional_history
+                .entry(key)
+                .or_insert_with(|| VecDeque::with_capacity(window));
+            if history.len() >= window {
+                history.pop_front();
+            }
+            history.push_back(notional);
+        }
+    }
+
+    // True when the book's current top-N notional is below `factor` times its
+    // recent mean. False without history (sampling disabled or just started).
+    fn is_thin(&self, symbol: &str, factor: Decimal) -> bool {
+        let symbol = symbol.to_uppercase();
+        // The history guard is released before the book is read
+        let mean = match self.notional_history.get(&symbol) {
+            Some(history) if !history.is_empty() => history.iter().sum::<Decimal>() / Decimal::from(history.len()),
+            _ => return false,
+        };
+        let levels = self.config.thinness_levels;
+        self.with_order_book(&symbol, |book| book.notional(levels) < mean * factor)
+            .unwrap_or(false)
+    }
+
+    // Append each synced book's spread to its history, dropping samples older than spread_history
+    fn sample_spreads(&self) {
+        let now = tokio::time::Instant::now();
+        let retention = self.config.spread_history;
+        for entry in self.store.order_books.iter().filter(|e| self.owns(e.key()) && e.value().synced) {
+            let Some(spread) = entry.value().spread() else {
+                continue;
+            };
+            let mut history = self.spread_history.entry(entry.key().1.clone()).or_default();
+            while history.front().is_some_and(|(at, _)| now.duration_since(*at) > retention) {
+                history.pop_front();
+            }
+            history.push_back((now, spread));
+        }
+    }
+
+    // Average spread over the last `window`, each sample weighted by how long it
+    // held: until the next sample, but at most one sample interval, so time the
+    // book spent unsynced (and unsampled) doesn't count. None without samples
+    // in the window.
+    fn time_weighted_spread(&self, symbol: &str, window: Duration) -> Option<Decimal> {
+        let interval = self.config.spread_sample_interval?;
+        let history = self.spread_history.get(&symbol.to_uppercase())?;
+        let now = tokio::time::Instant::now();
+        let start = now.checked_sub(window).unwrap_or_else(|| history.front().map_or(now, |(at, _)| *at));
+        let (mut weighted, mut total) = (Decimal::ZERO, Decimal::ZERO);
+        for (i, (at, spread)) in history.iter().enumerate() {
+            let next = history.get(i + 1).map_or(now, |(next, _)| *next);
+            let (from, until) = ((*at).max(start), next.min(*at + interval));
+            if until <= from {
+                continue;
+            }
+            let held = Decimal::from(until.duration_since(from).as_micros() as u64);
+            weighted += spread * held;
+            total += held;
+        }
+        (!total.is_zero()).then(|| weighted / total)
+    }
+
+    fn record_ofi(&self, key: &str, ofi: Decimal, window: Duration) {
+        let now = tokio::time::Instant::now();
+        let mut history = self.ofi_history.entry(key.to_string()).or_default();
+        while history.front().is_some_and(|(at, _)| now.duration_since(*at) > window) {
+            history.pop_front();
+        }
+        history.push_back((now, ofi));
+    }
+
+    // Order flow imbalance summed over the last ofi_window: positive when bid
+    // demand outweighed ask supply. None without ofi_window_ms or any update.
+    fn order_flow_imbalance(&self, symbol: &str) -> Option<Decimal> {
+        let window = self.config.ofi_window?;
+        let history = self.ofi_history.get(&symbol.to_uppercase())?;
+        let now = tokio::time::Instant::now();
+        Some(history.iter().filter(|(at, _)| now.duration_since(*at) <= window).map(|(_, ofi)| ofi).sum())
+    }
+
+    // Queue snapshot refreshes for the depth books of symbols that passed one of
+    // funding_refresh_offsets_ms since the last check. Times after a funding
+    // event are measured from the funding time the mark price reported before
+    // it rolled over. Returns the number of symbols refreshed.
+    fn check_funding_refreshes(&self) -> usize {
+        let offsets = &self.config.funding_refresh_offsets_ms;
+        let now_ms = unix_time_ms();
+        let mut refreshed = 0;
+        for mark in self.store.mark_prices.iter().filter(|r| self.owns(r.key())) {
+            let symbol = &mark.key().1;
+            let funding_time = mark.value().next_funding_time;
+            if funding_time == 0 {
+                continue;
+            }
+            // First sight of a symbol: only refresh for times still ahead
+            let mut state = self.funding_refresh.entry(symbol.clone()).or_insert((0, funding_time, now_ms));
+            let (previous, current, fired_until) = &mut *state;
+            if funding_time != *current {
+                (*previous, *current) = (*current, funding_time);
+            }
+            let due = [*previous, *current]
+                .into_iter()
+                .filter(|funding| *funding != 0)
+                .flat_map(|funding| offsets.iter().map(move |offset| funding.saturating_add_signed(*offset)))
+                .filter(|at| *at > *fired_until && *at <= now_ms)
+                .max();
+            let Some(due) = due else {
+                continue;
+            };
+            *fired_until = due;
+            drop(state);
+            info!("Refreshing {} order book around funding time", symbol);
+            for depth_stream in self.config.depth_streams() {
+                self.request_snapshot_refresh(&self.order_book_key(symbol, depth_stream), "funding time");
+            }
+            refreshed += 1;
+        }
+        refreshed
+    }
+
+    // Refresh synced books that have had no bids or no asks for longer than
+    // `timeout`. Removals can legitimately empty a side for a moment; one that
+    // stays empty means levels were lost, and the stream alone won't restore them.
+    // Returns the number of books sent for a refresh.
+    fn check_empty_sides(&self, timeout: Duration) -> usize {
+        let now = tokio::time::Instant::now();
+        let mut expired = Vec::new();
+        for entry in self.store.order_books.iter().filter(|e| self.owns(e.key())) {
+            let (key, book) = (&entry.key().1, entry.value());
+            if !book.synced || (!book.bids.is_empty() && !book.asks.is_empty()) {
+                self.empty_side_since.remove(key);
+                continue;
+            }
+            let since = *self.empty_side_since.entry(key.clone()).or_insert(now);
+            if now.duration_since(since) >= timeout {
+                let side = if book.bids.is_empty() { "bids" } else { "asks" };
+                expired.push((key.clone(), side));
+            }
+        }
+        for (key, side) in &expired {
+            warn!("Order book {} has had no {} for over {:?}", key, side, timeout);
+            self.empty_side_since.remove(key);
+            self.invalidate_book(key, "empty book side");
+        }
+        expired.len()
+    }
+
+    // Books still unsynced `deadline` after their last snapshot (or after
+    // `since` for books that never had one) that have retries left. Counts the
+    // retry for each returned book and forgets retries of books that synced.
+    fn books_missing_sync_deadline(&self, deadline: Duration, since: tokio::time::Instant) -> Vec<String> {
+        let max_retries = self.config.max_sync_retries;
+        let mut due = Vec::new();
+        for entry in self.store.order_books.iter().filter(|e| self.owns(e.key())) {
+            let (key, book) = (&entry.key().1, entry.value());
+            if book.synced {
+                self.sync_retries.remove(key);
+                continue;
+            }
+            // Partial depth books sync from their next frame, not a snapshot, and
+            // books with a refetch already in flight get their replay first
+            if self.is_depth_downgraded(key) || self.refresh_buffers.contains_key(key) {
+                continue;
+            }
+            if book.updated_at.unwrap_or(since).elapsed() < deadline {
+                continue;
+            }
+            let mut retries = self.sync_retries.entry(key.clone()).or_insert(0);
+            if *retries < max_retries {
+                *retries += 1;
+                warn!(
+                    "Order book {} not synced within {:?}, fetching a fresh snapshot (retry {}/{})",
+                    key, deadline, *retries, max_retries
+                );
+                due.push(key.clone());
+            } else if *retries == max_retries {
+                *retries += 1;
+                error!("Order book {} still not synced after {} snapshot retries, giving up", key, max_retries);
+                self.events.record(EventKind::Resync, Some(key), "sync retries exhausted");
+            }
+        }
+        due
+    }
+
+    // Check every book's invariants; a violating book is marked unsynced and
+    // refreshed from a snapshot. Returns the number of books that failed.
+    fn check_consistency(&self) -> usize {
+        let mut failed = 0;
+        for mut entry in self.store.order_books.iter_mut().filter(|e| self.owns(e.key())) {
+            let key = entry.key().1.clone();
+            let book = entry.value_mut();
+            let mut violations = Vec::new();
+            if let Some((price, _)) = book.bids.iter().chain(&book.asks).find(|(_, qty)| qty.is_zero()) {
+                violations.push(format!("zero quantity level at {}", price));
+            }
+            if book.synced {
+                if let (Some((bid, _)), Some((ask, _))) = (book.best_bid(), book.best_ask()) {
+                    if bid >= ask {
+                        violations.push(format!("crossed: bid {} >= ask {}", bid, ask));
+                    }
+                }
+                // Only compared across checks where the book stayed synced; a
+                // snapshot or sequence reset may legitimately move it back
+                if let Some(previous) = self.checked_update_ids.get(&key).map(|id| *id) {
+                    if book.last_update_id < previous {
+                        violations.push(format!("lastUpdateId went back from {} to {}", previous, book.last_update_id));
+                    }
+                }
+            }
+            if violations.is_empty() {
+                if book.synced {
+                    self.checked_update_ids.insert(key, book.last_update_id);
+                } else {
+                    self.checked_update_ids.remove(&key);
+                }
+                continue;
+            }
+
+            failed += 1;
+            let detail = violations.join("; ");
+            error!("Order book {} failed consistency check: {}", key, detail);
+            self.events.record(EventKind::Resync, Some(&key), format!("consistency check: {}", detail));
+            if book.synced {
+                book.synced = false;
+                book.desynced_at = Some(tokio::time::Instant::now());
+            }
+            self.checked_update_ids.remove(&key);
+            self.request_snapshot_refresh(&key, "consistency check failure");
+        }
+        failed
+    }
+
+    // Run the compaction pass over every book
+    fn compact_order_books(&self) {
+        let max_distance_bps = self.config.compaction_max_distance_bps;
+        for mut entry in self.store.order_books.iter_mut().filter(|e| self.owns(e.key())) {
+            let removed = entry.value_mut().compact(max_distance_bps);
+            if removed > 0 {
+                debug!("Compacted {} levels from {} order book", removed, entry.key().1);
+            }
+        }
+    }
+
+    // With quiet_start, whether the book's startup logs are still held back
+    fn quiet_until_synced(&self, key: &str) -> bool {
+        self.config.quiet_start
+            && !self.quiet_start_over.load(AtomicOrdering::Relaxed)
+            && !self.ever_synced.contains(key)
+    }
+
+    // Stop holding back startup logs, warning once for each book that hasn't
+    // synced yet so one that never does isn't silent forever
+    fn end_quiet_start(&self, after: Duration) {
+        self.quiet_start_over.store(true, AtomicOrdering::Relaxed);
+        for book in self.store.order_books.iter().filter(|e| self.owns(e.key())) {
+            let key = &book.key().1;
+            if !self.ever_synced.contains(key) {
+                warn!("Order book {} still not synced {:?} after startup", key, after);
+            }
+        }
+    }
+
+    // Called when a book becomes synced; logs the first time with quiet_start
+    fn note_synced(&self, key: &str) {
+        if self.config.quiet_start && self.ever_synced.insert(key.to_owned()) {
+            info!("Order book {} synced", key);
+        }
+    }
+
+    fn record_resync_duration(&self, symbol: &str, unsynced_for: Duration) {
+        info!("Order book {} resynced after {:?}", symbol, unsynced_for);
+        self.events.record(EventKind::Resync, Some(symbol), format!("resynced after {:?}", unsynced_for));
+        self.resync_durations
+            .entry(symbol.to_owned())
+            .or_insert_with(DurationHistogram::new)
+            .record(unsynced_for);
+    }
+
+    // How long books stayed unsynced, per symbol
+    fn resync_durations(&self, symbol: &str) -> Option<DurationHistogram> {
+        self.resync_durations.get(&symbol.to_uppercase()).map(|r| r.value().clone())
+    }
+
+    // Count a primary depth book's fall out of sync toward depth_downgrade_after,
+    // and switch the symbol to partial depth once it is reached. The switch
+    // takes a reconnect, since streams are only chosen on connect.
+    fn record_depth_desync(&self, key: &str) {
+        let Some(limit) = self.config.depth_downgrade_after else {
+            return;
+        };
+        // Books of additional depth speeds (SYMBOL@STREAM) follow the primary one
+        if key.contains('@') || self.depth_downgraded.contains(key) {
+            return;
+        }
+        let window = self.config.depth_downgrade_window;
+        let now = tokio::time::Instant::now();
+        let count = {
+            let mut desyncs = self.depth_desyncs.entry(key.to_string()).or_default();
+            desyncs.retain(|at| now.duration_since(*at) <= window);
+            desyncs.push_back(now);
+            desyncs.len()
+        };
+        if count < limit as usize {
+            return;
+        }
+        self.depth_desyncs.remove(key);
+        self.depth_downgraded.insert(key.to_string());
+        warn!(
+            "Order book {} desynced {} times within {:?}, downgrading to {} until restart",
+            key, count, window, PARTIAL_DEPTH_STREAM
+        );
+        self.events.record(
+            EventKind::DepthDowngrade,
+            Some(key),
+            format!("{} desyncs within {:?}, now on {}", count, window, PARTIAL_DEPTH_STREAM),
+        );
+        self.force_reconnect(true);
+    }
+
+    fn is_depth_downgraded(&self, symbol: &str) -> bool {
+        !self.depth_downgraded.is_empty() && self.depth_downgraded.contains(&symbol.to_uppercase())
+    }
+
+    // Swap diff depth streams of downgraded symbols for PARTIAL_DEPTH_STREAM,
+    // keeping one per symbol when several depth speeds were subscribed
+    fn downgrade_depth_streams(&self, streams: Vec<String>) -> Vec<String> {
+        if self.depth_downgraded.is_empty() {
+            return streams;
+        }
+        let mut downgraded = Vec::with_capacity(streams.len());
+        for stream in streams {
+            let stream = match stream.split_once('@') {
+                Some((symbol, name)) if name.parse() == Ok(StreamType::Depth) && self.is_depth_downgraded(symbol) => {
+                    format!("{}@{}", symbol, PARTIAL_DEPTH_STREAM)
+                }
+                _ => stream,
+            };
+            if !downgraded.contains(&stream) {
+                downgraded.push(stream);
+            }
+        }
+        downgraded
+    }
+
+    // Replace a downgraded symbol's book with a partial depth payload
+    fn apply_partial_depth(&self, symbol: &str, update: DepthUpdateData) {
+        let key = symbol.to_uppercase();
+        let store_key = self.key(&key);
+        self.touch(&store_key, StreamType::Depth);
+        let mut book = self.store.order_books
+            .entry(store_key)
+            .or_insert_with(|| OrderBook::new(key.clone()));
+        let was_synced = book.synced;
+        book.apply_partial(&update);
+        if !was_synced {
+            if let Some(desynced_at) = book.desynced_at.take() {
+                self.record_resync_duration(&key, desynced_at.elapsed());
+            }
+            self.note_synced(&key);
+        }
+        // Partial frames are whole views, so delta subscribers get snapshots
+        self.publish_full_book(&book);
+    }
+
+    // `symbol` is the book's cache key, see order_book_key
+    fn update_order_book(&self, symbol: String, update: DepthUpdateData) {
+        if self.config.validate_tick_size {
+            self.check_tick_size(&symbol, &update);
+        }
+        match self.store.order_books.entry(self.key(&symbol)) {
+            dashmap::mapref::entry::Entry::Occupied(mut entry) => {
+                let book = entry.get_mut();
+                let was_synced = book.synced;
+                // A book restored from a fresh checkpoint syncs if the stream picks
+                // up right after it, and is refetched otherwise
+                if !was_synced && self.restored_books.remove(&symbol).is_some() {
+                    if update.prev_final_update_id == book.last_update_id {
+                        book.synced = true;
+                    } else {
+                        self.request_snapshot_refresh(&symbol, "checkpoint behind the stream");
+                    }
+                }
+                self.buffer_for_refresh(&symbol, &update);
+                let result = match self.config.sequence_reset_threshold {
+                    Some(threshold) if update.final_update_id.saturating_add(threshold) < book.last_update_id => {
+                        Err(OrderBookError::SequenceReset {
+                            final_update_id: update.final_update_id,
+                            last_update_id: book.last_update_id,
+                        })
+                    }
+                    _ => book.apply_update(&update),
+                };
+                if let Err(e) = result {
+                    // Rejections before a book's first sync are startup noise under quiet_start
+                    if was_synced || !self.quiet_until_synced(&symbol) {
+                        warn!("Order book {} needs resyncing: {}", symbol, e);
+                    }
+                    if matches!(e, OrderBookError::SequenceReset { .. }) {
+                        book.rejected_updates += 1;
+                        // Forget the old sequence so later updates are plain gaps until
+                        // the new snapshot lands, rather than triggering more refreshes
+                        book.last_update_id = 0;
+                        self.request_snapshot_refresh(&symbol, "sequence reset");
+                    }
+                    // The stream carries on past the bad update, so only a snapshot recovers
+                    if was_synced && matches!(e, OrderBookError::InvalidLevel { .. }) {
+                        self.request_snapshot_refresh(&symbol, "invalid level");
+                    }
+                    // Mark for resync
+                    book.synced = false;
+                    if was_synced {
+                        book.desynced_at = Some(tokio::time::Instant::now());
+                        // Only on the transition out of sync; unsynced books reject
+                        // every buffered event and would flood the log
+                        if let Some(levels) = self.config.desync_dump_levels {
+                            log_desync_ladder(book, &update, levels);
+                        }
+                        self.record_depth_desync(&symbol);
+                    }
+                    self.events.record(
+                        EventKind::Resync,
+                        Some(&symbol),
+                        format!("rejected update: {}", e),
+                    );
+                } else {
+                    self.touch(&self.key(&symbol), StreamType::Depth);
+                    if let Some(window) = self.config.ofi_window {
+                        self.record_ofi(&symbol, book.last_ofi, window);
+                    }
+                    if !was_synced {
+                        if let Some(desynced_at) = book.desynced_at.take() {
+                            self.record_resync_duration(&symbol, desynced_at.elapsed());
+                        }
+                        self.note_synced(&symbol);
+                    }
+                    if self.event_tx.receiver_count() > 0 {
+                        let event = match self.config.book_broadcast {
+                            BookBroadcastMode::Snapshot => {
+                                MarketEvent::OrderBookUpdate(OrderBookUpdateEvent::from_book(book, self.config.broadcast_depth))
+                            }
+                            // The syncing update is already reflected in the full book
+                            BookBroadcastMode::Delta if !was_synced => {
+                                MarketEvent::OrderBookSnapshot(OrderBookUpdateEvent::from_book(book, usize::MAX))
+                            }
+                            BookBroadcastMode::Delta => {
+                                MarketEvent::OrderBookDelta(OrderBookDeltaEvent::from_update(book, &update))
+                            }
+                        };
+                        self.publish(event);
+                    }
+                }
+            }
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                self.buffer_for_refresh(&symbol, &update);
+                let mut book = OrderBook::new(symbol.clone());
+                book.min_qty = self.min_level_qty(&symbol);
+                book.group_tick = self.group_tick(&symbol);
+                if let Err(e) = book.apply_update(&update) {
+                    if !self.quiet_until_synced(&symbol) {
+                        debug!("Created new order book for {}, waiting for sync: {}", symbol, e);
+                    }
+                }
+                entry.insert(book);
+            }
+        }
+    }
+}
+
+// Cap on how many clients run connect() at once, shared by clients that split
+// one workload across several connections (by symbol or by market). connect()
+// fetches a REST snapshot per depth symbol, so after an outage every client
+// reconnecting together would spend the REST weight budget in one burst; with a
+// shared limiter they queue and recover a few at a time instead. Snapshot
+// refetches outside connect() take a slot too, so they queue behind it.
+#[derive(Clone)]
+struct ConnectLimiter(Arc<tokio::sync::Semaphore>);
+
+impl ConnectLimiter {
+    // `concurrency` clients may connect at a time; at least one always can
+    #[allow(dead_code)]
+    fn new(concurrency: usize) -> Self {
+        Self(Arc::new(tokio::sync::Semaphore::new(concurrency.max(1))))
+    }
+
+    // Wait for a slot; it's released when the permit is dropped
+    async fn acquire(&self, purpose: &str) -> Option<tokio::sync::OwnedSemaphorePermit> {
+        if self.0.available_permits() == 0 {
+            info!("Waiting for a {} slot shared with other clients", purpose);
+        }
+        self.0.clone().acquire_owned().await.ok()
+    }
+}
+
+// Result of building the combined stream subscription
+#[derive(Debug, Clone, Default)]
+struct SubscriptionSummary {
+    subscribed: Vec<String>,
+    // Streams left out of the subscription, with the reason
+    rejected: Vec<(String, String)>,
+}
+
+// WebSocket client for Binance API
+struct BinanceWebSocketClient {
+    ws_stream: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    symbols: Vec<String>,
+    cache: Arc<MarketDataCache>,
+    http_client: HttpClient,
+    config: ClientConfig,
+    background_tasks: Vec<tokio::task::JoinHandle<()>>,
+    // Set by a force_reconnect(true) for the next connect
+    preserve_books_on_connect: bool,
+    // Streams the last successful connect subscribed to; empty before the first connect
+    active_streams: Vec<String>,
+    // Trusts only the pinned certificates when tls_ca_file is set; None uses the system roots
+    tls_connector: Option<native_tls::TlsConnector>,
+    // Frame handling pool when message_workers is set. Kept across reconnects so
+    // a symbol's frames from consecutive sessions stay on one ordered queue.
+    message_workers: Option<message_workers::MessageWorkers>,
+    // Shared with other clients through share_connect_limiter; None connects freely
+    connect_limiter: Option<ConnectLimiter>,
+    // Destination of archive_trades when trade_archive_dir is set, shared by the
+    // periodic task and the final write on shutdown
+    trade_archive: Option<Arc<Mutex<TradeArchive>>>,
+}
+
+impl BinanceWebSocketClient {
+    fn new(symbols: Vec<String>) -> Result<Self, WebSocketError> {
+        Self::with_config(ClientConfig {
+            symbols,
+            ..ClientConfig::default()
+        })
+    }
+
+    // Fails only if the pinned TLS certificates can't be loaded
+    fn with_config(mut config: ClientConfig) -> Result<Self, WebSocketError> {
+        config.symbols = dedup_symbols(std::mem::take(&mut config.symbols));
+        let mut http_client = HttpClient::builder();
+        let mut tls_connector = None;
+        if let Some(path) = &config.tls_ca_file {
+            let pems = load_pem_certificates(path)?;
+            let mut builder = native_tls::TlsConnector::builder();
+            builder.disable_built_in_roots(true);
+            http_client = http_client.tls_built_in_root_certs(false);
+            for pem in &pems {
+                builder.add_root_certificate(native_tls::Certificate::from_pem(pem.as_bytes())?);
+                http_client = http_client.add_root_certificate(reqwest::Certificate::from_pem(pem.as_bytes())?);
+            }
+            info!("Pinned {} TLS certificate(s) from {}", pems.len(), path.display());
+            tls_connector = Some(builder.build()?);
+        }
+        Ok(Self {
+            ws_stream: None,
+            symbols: config.symbols.clone(),
+            cache: Arc::new(MarketDataCache::new(&config)),
+            http_client: http_client.build()?,
+            tls_connector,
+            message_workers: None,
+            connect_limiter: None,
+            trade_archive: config.trade_archive_dir.clone().map(|dir| Arc::new(Mutex::new(TradeArchive::new(&config, dir)))),
+            config,
+            background_tasks: Vec::new(),
+            preserve_books_on_connect: false,
+            active_streams: Vec::new(),
+        })
+    }
+
+    async fn fetch_order_book_snapshot(&self, symbol: &str) -> Result<OrderBookSnapshot, WebSocketError> {
+        fetch_snapshot(&self.http_client, &self.config, symbol).await
+    }
+
+    // Fetch and cache exchangeInfo trading rules (tick size, step size, status)
+    async fn fetch_exchange_info(&self) -> Result<usize, WebSocketError> {
+        let url = format!("{}{}", self.config.api_url(), self.config.market.exchange_info_path());
+        debug!("Fetching exchange info");
+        let body = self.http_client.get(&url).send().await?.text().await?;
+        let info: ExchangeInfo = serde_json::from_str(&body)?;
+        let count = info.symbols.len();
+        for raw in info.symbols {
+            let info = SymbolInfo::from(raw);
+            self.cache.store.symbol_info.insert(self.cache.key(&info.symbol), info);
+        }
+        Ok(count)
+    }
+
+    // Pre-flight check of the configured symbols against cached exchangeInfo, so
+    // a typo fails loudly instead of producing a book that never syncs
+    fn validate_symbols(&self) -> Result<(), WebSocketError> {
+        let policy = self.config.symbol_validation;
+        if policy == SymbolValidation::Off || !self.config.fetch_exchange_info {
+            return Ok(());
+        }
+        if !self.cache.store.symbol_info.iter().any(|info| self.cache.owns(info.key())) {
+            warn!("Exchange info unavailable, skipping symbol validation");
+            return Ok(());
+        }
+
+        let mut unknown = Vec::new();
+        for symbol in &self.symbols {
+            match self.cache.store.symbol_info.get(&self.cache.key(&symbol.to_uppercase())) {
+                None => unknown.push(symbol.to_uppercase()),
+                Some(info) => {
+                    if let Some(status) = info.status.as_deref().filter(|s| *s != "TRADING") {
+                        warn!("Symbol {} has status {}", symbol, status);
+                    }
+                }
+            }
+        }
+        if unknown.is_empty() {
+            return Ok(());
+        }
+
+        match policy {
+            SymbolValidation::Error => Err(WebSocketError::UnknownSymbols(unknown)),
+            _ => {
+                warn!("Symbols not listed in exchange info: {}", unknown.join(", "));
+                Ok(())
+            }
+        }
+    }
+
+    // Fetch snapshots for every symbol, or with preserve_synced only for symbols
+    // with a depth book that isn't synced
+    async fn initialize_order_books(&self, preserve_synced: bool) -> Result<(), WebSocketError> {
+        let symbols = self.symbols
+            .iter()
+            .filter(|symbol| self.config.depth_streams().next().is_some() && self.config.subscribes(symbol, StreamType::Depth))
+            .filter(|symbol| !self.cache.is_depth_downgraded(symbol))
+            .filter(|symbol| {
+                !self.config.depth_streams().all(|stream| {
+                    let key = self.cache.order_book_key(symbol, stream);
+                    self.cache.restored_books.contains(&key)
+                        || preserve_synced && self.cache.store.order_books.get(&self.cache.key(&key)).is_some_and(|book| book.synced)
+                })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if preserve_synced {
+            info!("Preserving synced books, fetching snapshots for {} of {} symbols", symbols.len(), self.symbols.len());
+        }
+        let mut fetches = futures_util::stream::iter(symbols)
+            .map(|symbol| async move {
+                let result = self.fetch_order_book_snapshot(&symbol).await;
+                // Avoid rate limiting
+                tokio::time::sleep(Duration::from_millis(SNAPSHOT_FETCH_DELAY_MS)).await;
+                (symbol, result)
+            })
+            .buffer_unordered(self.config.snapshot_concurrency);
+
+        while let Some((symbol, result)) = fetches.next().await {
+            let snapshot = match result {
+                Ok(snapshot) => snapshot,
+                Err(WebSocketError::JsonError(e)) if !self.config.fatal_snapshot_parse_errors => {
+                    warn!("Skipping order book init for {}: unparseable snapshot: {}", symbol, e);
+                    self.cache.events.record(EventKind::ParseError, Some(&symbol.to_uppercase()), format!("snapshot: {}", e));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            // Every depth speed shares the same update id sequence, so one snapshot seeds them all
+            for depth_stream in self.config.depth_streams() {
+                let key = self.cache.order_book_key(&symbol, depth_stream);
+                self.cache.install_snapshot(&key, snapshot.clone());
+                if !self.cache.quiet_until_synced(&key) {
+                    info!("Initialized order book for {}", key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Force-refresh a single book from a fresh REST snapshot, without waiting for
+    // a reconnect. Returns the new best bid and best ask.
+    #[allow(dead_code)]
+    async fn refresh_book(
+        &self,
+        symbol: &str,
+    ) -> Result<(Option<(Decimal, Decimal)>, Option<(Decimal, Decimal)>), WebSocketError> {
+        let key = symbol.to_uppercase();
+        self.cache.begin_snapshot_refresh(&key);
+        let permit = match &self.connect_limiter {
+            Some(limiter) => limiter.acquire("snapshot refetch").await,
+            None => None,
+        };
+        let fetched = self.fetch_order_book_snapshot(symbol).await;
+        drop(permit);
+        let snapshot = match fetched {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                self.cache.abandon_snapshot_refresh(&key);
+                return Err(e);
+            }
+        };
+        let book = self.cache.install_refreshed_snapshot(&key, snapshot);
+        info!("Refreshed order book for {}", symbol);
+        Ok((book.best_bid(), book.best_ask()))
+    }
+
+    #[instrument(skip(self))]
+    async fn connect(&mut self) -> Result<SubscriptionSummary, WebSocketError> {
+        // First initialize order books with snapshots
+        let preserve_books = std::mem::take(&mut self.preserve_books_on_connect);
+        self.initialize_order_books(preserve_books).await?;
+
+        let requested = self.symbols
+            .iter()
+            .flat_map(|symbol| {
+                let s = self.config.stream_name_case.apply(symbol);
+                self.config.streams
+                    .iter()
+                    .filter(|stream| stream.parse().is_ok_and(|t| self.config.subscribes(symbol, t)))
+                    .map(move |stream| format!("{}@{}", s, stream))
+            })
+            .chain(self.config.firehose_streams.iter().cloned())
+            .collect::<Vec<_>>();
+
+        let requested = self.cache.downgrade_depth_streams(requested);
+
+        let mut summary = SubscriptionSummary::default();
+        let combined = self.config.combined_endpoint()?;
+        let mut url_len = combined.as_str().len() + "?streams=".len();
+        for stream in requested {
+            if summary.subscribed.len() >= MAX_STREAMS_PER_CONNECTION {
+                summary.rejected.push((stream, format!("exceeds {} streams per connection", MAX_STREAMS_PER_CONNECTION)));
+            } else if url_len + stream.len() + 1 > MAX_STREAM_URL_LEN {
+                summary.rejected.push((stream, format!("exceeds {} character URL limit", MAX_STREAM_URL_LEN)));
+            } else {
+                url_len += stream.len() + 1;
+                summary.subscribed.push(stream);
+            }
+        }
+
+        let url = match &self.cache.raw_stream {
+            // validate() guarantees this is the only stream
+            Some(stream) => {
+                let mut url = self.config.raw_endpoint()?;
+                let path = format!("{}/{}", url.path().trim_end_matches('/'), stream);
+                url.set_path(&path);
+                url
+            }
+            None => {
+                let mut url = combined;
+                url.set_query(Some(&format!("streams={}", summary.subscribed.join("/"))));
+                url
+            }
+        };
+        
+        let connector = self.tls_connector.clone().map(Connector::NativeTls);
+        let ws_config = WebSocketConfig {
+            max_message_size: Some(self.config.max_message_size),
+            max_frame_size: Some(self.config.max_message_size),
+            ..WebSocketConfig::default()
+        };
+        let (ws_stream, _) = connect_async_tls_with_config(url, Some(ws_config), false, connector).await?;
+        self.ws_stream = Some(ws_stream);
+        self.active_streams = summary.subscribed.clone();
+        Ok(summary)
+    }
+
+    #[instrument(skip(self))]
+    // Returns the server's close frame if the session ended with one
+    async fn process_messages(&mut self) -> Result<Option<CloseFrame<'static>>, WebSocketError> {
+        let batch_size = self.config.batch_size;
+        let mut book_ticker_batch = Vec::with_capacity(batch_size);
+        let mut trade_batch = Vec::with_capacity(batch_size);
+        let mut last_flush = tokio::time::Instant::now();
+        
+        // Frames received while paused under PausePolicy::Buffer, with their receive
+        // stamps. The session goes by the pause state it last saw, so frames that
+        // arrive around a resume are handled after the backlog, not before it.
+        let mut paused_backlog: VecDeque<(String, Option<u64>)> = VecDeque::new();
+        let mut pause_state = self.cache.paused.subscribe();
+        let mut paused = *pause_state.borrow_and_update();
+        // Set once a frame is discarded while paused; the books get refetched on resume
+        let mut frames_dropped = false;
+        
+        // A request left over from before this session was already served by the
+        // connect that started it
+        let mut reconnect_requested = self.cache.reconnect_request.subscribe();
+        self.cache.take_reconnect_request();
+
+        let (write, mut read) = self.ws_stream.as_mut().unwrap().split();
+        let write = Arc::new(tokio::sync::Mutex::new(write));
+
+        loop {
+            tokio::select! {
+                _ = flush_batch(
+                    &mut book_ticker_batch,
+                    &mut trade_batch,
+                    &mut last_flush,
+                    self.cache.clone(),
+                ) => {}
+
+                Ok(()) = reconnect_requested.changed() => {
+                    // Clearing a request also counts as a change
+                    if reconnect_requested.borrow_and_update().is_none() {
+                        continue;
+                    }
+                    info!("Reconnect requested, closing connection");
+                    // Best effort; the socket is dropped either way
+                    let _ = write.lock().await.send(Message::Close(None)).await;
+                    break Ok(None);
+                }
+
+                Ok(()) = pause_state.changed() => {
+                    paused = *pause_state.borrow_and_update();
+                    if paused {
+                        continue;
+                    }
+                    // Request the refetches first, so the replayed updates are
+                    // buffered for them
+                    if std::mem::take(&mut frames_dropped) {
+                        self.cache.request_snapshot_refresh_all("frames dropped while paused");
+                    }
+                    if !paused_backlog.is_empty() {
+                        info!("Resumed, replaying {} buffered frames", paused_backlog.len());
+                        for (buffered, buffered_at_ms) in paused_backlog.drain(..) {
+                            match &self.message_workers {
+                                Some(workers) => workers.dispatch(buffered, buffered_at_ms).await,
+                                None => handle_message(
+                                    &buffered,
+                                    buffered_at_ms,
+                                    &mut book_ticker_batch,
+                                    &mut trade_batch,
+                                    batch_size,
+                                    self.cache.clone(),
+                                ).await?,
+                            }
+                        }
+                    }
+                }
+
+                msg = read.next() => {
+                    let msg = match msg.transpose()? {
+                        Some(m) => m,
+                        None => break Ok(None),
+                    };
+                    if let Message::Text(text) = &msg {
+                        self.cache.record_ingress(text.len());
+                    }
+                    let received_at_ms = self.config.stamp_receive_time.then(unix_time_ms);
+
+                    match msg {
+                        Message::Text(text) if paused => match self.config.pause_policy {
+                            PausePolicy::Drop => frames_dropped = true,
+                            PausePolicy::Buffer => {
+                                if paused_backlog.len() >= self.config.pause_buffer_capacity {
+                                    paused_backlog.pop_front();
+                                    if !frames_dropped {
+                                        frames_dropped = true;
+                                        warn!("Pause buffer full, dropping oldest frames; depth books will be refetched on resume");
+                                        self.cache.events.record(EventKind::Resync, None, "pause buffer overflow");
+                                    }
+                                }
+                                paused_backlog.push_back((text, received_at_ms));
+                            }
+                        },
+                        Message::Text(text) => {
+                            match &self.message_workers {
+                                Some(workers) => workers.dispatch(text, received_at_ms).await,
+                                None => handle_message(
+                                    &text,
+                                    received_at_ms,
+                                    &mut book_ticker_batch,
+                                    &mut trade_batch,
+                                    batch_size,
+                                    self.cache.clone(),
+                                ).await?,
+                            }
+                        }
+                        Message::Ping(data) => {
+                            write.lock().await.send(Message::Pong(data)).await?;
+                        }
+                        Message::Close(frame) => {
+                            debug!("Received close frame");
+                            break Ok(frame);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    // Count a failed connection or dropped session toward the reconnect limit,
+    // unless we are still inside the startup warmup window
+    fn register_failure(&mut self, started_at: tokio::time::Instant) {
+        if started_at.elapsed() < self.config.warmup_period {
+            debug!("Failure during warmup period, not counted toward reconnect limit");
+            return;
+        }
+        self.cache.reconnect_attempts.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    // Feed a recorded frame log (one raw WebSocket text frame per line) through
+    // the live handling path, pacing frames by the gaps between their event
+    // times divided by `speed`. An infinite speed replays as fast as possible.
+    // Batches flush on the configured interval as they would live, so flush
+    // timing relative to the frames is reproduced. Frames without an event
+    // time go out right after the previous one. Returns the frames replayed.
+    async fn replay_timed<R: std::io::BufRead>(&self, reader: R, speed: f64) -> Result<usize, WebSocketError> {
+        if speed.is_nan() || speed <= 0.0 {
+            return Err(WebSocketError::ConfigError(format!("replay speed must be > 0, got {}", speed)));
+        }
+        let cache = self.cache.clone();
+        let batch_size = self.config.batch_size;
+        let mut book_ticker_batch = Vec::with_capacity(batch_size);
+        let mut trade_batch = Vec::with_capacity(batch_size);
+        let mut last_flush = tokio::time::Instant::now();
+
+        // Event time and instant of the first paced frame; later frames are
+        // scheduled against it so sleep overshoot doesn't accumulate
+        let mut origin: Option<(u64, tokio::time::Instant)> = None;
+        let mut replayed = 0;
+        for line in reader.lines() {
+            let text = line?;
+            if text.trim().is_empty() {
+                continue;
+            }
+            let due = match (frame_event_time(&text), origin) {
+                (Some(event_time), Some((origin_time, origin_instant))) if speed.is_finite() => {
+                    let gap = Duration::from_millis(event_time.saturating_sub(origin_time));
+                    origin_instant + gap.div_f64(speed)
+                }
+                (Some(event_time), None) => {
+                    let now = tokio::time::Instant::now();
+                    origin = Some((event_time, now));
+                    now
+                }
+                _ => tokio::time::Instant::now(),
+            };
+            loop {
+                tokio::select! {
+                    _ = flush_batch(
+                        &mut book_ticker_batch,
+                        &mut trade_batch,
+                        &mut last_flush,
+                        cache.clone(),
+                    ) => {}
+                    _ = tokio::time::sleep_until(due) => break,
+                }
+            }
+
+            cache.record_ingress(text.len());
+            let received_at_ms = self.config.stamp_receive_time.then(unix_time_ms);
+            handle_message(&text, received_at_ms, &mut book_ticker_batch, &mut trade_batch, batch_size, cache.clone()).await?;
+            replayed += 1;
+        }
+
+        // End of the recording; apply what is still batched
+        if !book_ticker_batch.is_empty() {
+            cache.batch_update_book_tickers(book_ticker_batch);
+        }
+        if !trade_batch.is_empty() {
+            cache.batch_update_trades(trade_batch);
+        }
+        Ok(replayed)
+    }
+
+    // Root span for everything this client logs, carrying the instance label if set
+    fn instance_span(&self) -> tracing::Span {
+        match &self.config.instance_id {
+            Some(id) => tracing::info_span!("instance", id = %id),
+            None => tracing::Span::none(),
+        }
+    }
+
+    // Spawn background maintenance tasks that live for the duration of run().
+    // They run in the caller's span so instance labels carry over.
+    fn spawn_background_tasks(&self) -> Vec<tokio::task::JoinHandle<()>> {
+        let mut tasks = Vec::new();
+
+        if self.config.quiet_start {
+            let cache = self.cache.clone();
+            let bound = self.config.sync_deadline.unwrap_or(self.config.warmup_period);
+            tasks.push(tokio::spawn(async move {
+                tokio::time::sleep(bound).await;
+                cache.end_quiet_start(bound);
+            }.in_current_span()));
+        }
+
+        let cache = self.cache.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(INGRESS_SAMPLE_INTERVAL_SECS));
+            let mut previous = cache.ingress_totals();
+            let mut last_sample = tokio::time::Instant::now();
+            loop {
+                interval.tick().await;
+                let elapsed = last_sample.elapsed();
+                cache.sample_ingress(&mut previous, elapsed);
+                cache.sample_flicker(elapsed);
+                cache.sample_acceptance();
+                last_sample = tokio::time::Instant::now();
+            }
+        }.in_current_span()));
+
+        if let Some(period) = self.config.compaction_interval {
+            let cache = self.cache.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    cache.compact_order_books();
+                }
+            }.in_current_span()));
+        }
+
+        if let Some(deadline) = self.config.sync_deadline {
+            let cache = self.cache.clone();
+            tasks.push(tokio::spawn(async move {
+                let started_at = tokio::time::Instant::now();
+                let mut interval = tokio::time::interval(Duration::from_millis(SYNC_CHECK_INTERVAL_MS));
+                loop {
+                    interval.tick().await;
+                    for key in cache.books_missing_sync_deadline(deadline, started_at) {
+                        cache.request_snapshot_refresh(&key, "sync deadline");
+                    }
+                }
+            }.in_current_span()));
+        }
+
+        // Snapshot refetches requested on divergence, sequence resets, missed
+        // sync deadlines and the like
+        let cache = self.cache.clone();
+        let http_client = self.http_client.clone();
+        let config = self.config.clone();
+        let limiter = self.connect_limiter.clone();
+        tasks.push(tokio::spawn(async move {
+            loop {
+                cache.snapshot_refresh_notify.notified().await;
+                for (key, reason) in cache.take_snapshot_refresh() {
+                    // Extra depth speeds are keyed SYMBOL@STREAM; the REST symbol is the prefix
+                    let symbol = key.split('@').next().unwrap_or(&key);
+                    let _permit = match &limiter {
+                        Some(limiter) => limiter.acquire("snapshot refetch").await,
+                        None => None,
+                    };
+                    match fetch_snapshot(&http_client, &config, symbol).await {
+                        Ok(snapshot) => {
+                            cache.install_refreshed_snapshot(&key, snapshot);
+                            cache.events.record(EventKind::Resync, Some(&key), format!("snapshot refetched after {}", reason));
+                        }
+                        Err(e) => {
+                            cache.abandon_snapshot_refresh(&key);
+                            warn!("Failed to refetch snapshot for {}: {}", key, e);
+                        }
+                    }
+                }
+            }
+        }.in_current_span()));
+
+        if let Some(period) = self.config.consistency_check_interval {
+            let cache = self.cache.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    cache.check_consistency();
+                }
+            }.in_current_span()));
+        }
+
+        if let Some(timeout) = self.config.empty_side_timeout {
+            let cache = self.cache.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_millis(EMPTY_SIDE_CHECK_INTERVAL_MS));
+                loop {
+                    interval.tick().await;
+                    cache.check_empty_sides(timeout);
+                }
+            }.in_current_span()));
+        }
+
+        if !self.config.funding_refresh_offsets_ms.is_empty() {
+            let cache = self.cache.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_millis(FUNDING_REFRESH_CHECK_INTERVAL_MS));
+                loop {
+                    interval.tick().await;
+                    cache.check_funding_refreshes();
+                }
+            }.in_current_span()));
+        }
+
+        if let Some(period) = self.config.thinness_sample_interval {
+            let cache = self.cache.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    cache.sample_notional();
+                }
+            }.in_current_span()));
+        }
+
+        if let Some(period) = self.config.spread_sample_interval {
+            let cache = self.cache.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    cache.sample_spreads();
+                }
+            }.in_current_span()));
+        }
+
+        if let Some(path) = self.config.checkpoint_path.clone() {
+            let cache = self.cache.clone();
+            let period = self.config.checkpoint_interval;
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    match cache.save_checkpoint(&path) {
+                        Ok(count) => debug!("Checkpointed {} order books", count),
+                        Err(e) => warn!("Failed to write checkpoint {}: {}", path.display(), e),
+                    }
+                }
+            }.in_current_span()));
+        }
+
+        if let Some(archive) = self.trade_archive.clone() {
+            let cache = self.cache.clone();
+            let period = self.config.trade_archive_interval;
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    let (cache, archive) = (cache.clone(), archive.clone());
+                    match tokio::task::spawn_blocking(move || cache.archive_trades(&archive)).await {
+                        Ok(Ok(count)) => debug!("Archived {} trades", count),
+                        Ok(Err(e)) => warn!("Failed to archive trades, will retry: {}", e),
+                        Err(e) => warn!("Trade archive write panicked, will retry: {}", e),
+                    }
+                }
+            }.in_current_span()));
+        }
+
+        #[cfg(feature = "parquet")]
+        if let Some(dir) = self.config.parquet_dir.clone() {
+            info!("Writing trades and book tickers as Parquet to {}", dir.display());
+            let sink = parquet_sink::ParquetSink::new(
+                dir,
+                self.config.parquet_rotate_interval,
+                self.config.parquet_max_rows,
+            );
+            tasks.push(tokio::spawn(sink.run(self.cache.subscribe()).in_current_span()));
+        }
+
+        if let Some(target) = self.config.udp_top_of_book_addr {
+            let sink = udp_sink::UdpTopOfBookSink::new(target);
+            tasks.push(tokio::spawn(sink.run(self.cache.subscribe()).in_current_span()));
+        }
+
+        #[cfg(feature = "grpc")]
+        if let Some(addr) = self.config.grpc_addr {
+            let service = grpc::MarketDataService::new(Arc::clone(&self.cache));
+            tasks.push(tokio::spawn(service.run(addr).in_current_span()));
+        }
+
+        tasks
+    }
+
+    #[instrument(skip(self))]
+    async fn run(&mut self) -> Result<(), WebSocketError> {
+        if self.config.fetch_exchange_info {
+            match self.fetch_exchange_info().await {
+                Ok(count) => info!("Cached exchange info for {} symbols", count),
+                Err(e) => warn!("Failed to fetch exchange info: {}", e),
+            }
+        }
+        self.validate_symbols()?;
+
+        // After exchangeInfo, so restored books get step-size based dust thresholds
+        if let Some(path) = self.config.checkpoint_path.clone().filter(|p| p.exists()) {
+            // Reading and decoding deep books takes a while; keep it off the runtime
+            let cache = self.cache.clone();
+            let loaded = tokio::task::spawn_blocking({
+                let path = path.clone();
+                move || cache.load_checkpoint(&path)
+            })
+            .await
+            .map_err(|e| WebSocketError::IoError(std::io::Error::other(e)))
+            .and_then(|result| result);
+            match loaded {
+                Ok(count) => info!("Restored {} order books from checkpoint {}", count, path.display()),
+                Err(e) => warn!("Failed to restore checkpoint {}: {}", path.display(), e),
+            }
+        }
+
+        self.background_tasks = self.spawn_background_tasks();
+        if self.config.message_workers > 0 {
+            self.message_workers = Some(message_workers::MessageWorkers::spawn(
+                self.config.message_workers,
+                MESSAGE_WORKER_QUEUE_CAPACITY,
+                self.cache.clone(),
+            ));
+        }
+        let result = self.run_connection_loop().await;
+        // Drain queued frames before stopping, so a final checkpoint includes them
+        if let Some(workers) = self.message_workers.take() {
+            workers.shutdown().await;
+        }
+        self.stop_background_tasks();
+        result
+    }
+
+    fn stop_background_tasks(&mut self) {
+        for task in self.background_tasks.drain(..) {
+            task.abort();
+        }
+    }
+
+    // Stop background work and flush a final checkpoint so a restart resumes
+    // from current state rather than the last periodic write
+    async fn shutdown(&mut self) -> Result<(), WebSocketError> {
+        self.stop_background_tasks();
+        if let Some(archive) = self.trade_archive.clone() {
+            // Waits out a periodic write still in flight, since aborting its task
+            // doesn't stop the blocking write itself
+            let cache = self.cache.clone();
+            let count = tokio::task::spawn_blocking(move || cache.archive_trades(&archive))
+                .await
+                .map_err(|e| WebSocketError::IoError(std::io::Error::other(e)))??;
+            info!("Archived the last {} trades", count);
+        }
+        if let Some(path) = &self.config.checkpoint_path {
+            let count = self.cache.save_checkpoint(path)?;
+            info!("Wrote final checkpoint of {} order books to {}", count, path.display());
+        }
+        Ok(())
+    }
+
+    async fn run_connection_loop(&mut self) -> Result<(), WebSocketError> {
+        let started_at = tokio::time::Instant::now();
+        let mut reconnecting = false;
+        loop {
+            if self.cache.reconnect_attempts() >= MAX_RECONNECT_ATTEMPTS {
+                return Err(WebSocketError::MaxReconnectAttempts);
+            }
+
+            // Held through the snapshot fetches and handshake, released once connected
+            let permit = match &self.connect_limiter {
+                Some(limiter) => limiter.acquire("connect").await,
+                None => None,
+            };
+            let connected = self.connect().await;
+            drop(permit);
+
+            match connected {
+                Ok(summary) => {
+                    info!("Connected to Binance WebSocket with {} streams", summary.subscribed.len());
+                    for (stream, reason) in &summary.rejected {
+                        warn!("Stream {} not subscribed: {}", stream, reason);
+                    }
+                    self.cache.events.record(EventKind::Connected, None, "connected");
+                    self.cache.publish(MarketEvent::Connected { streams: summary.subscribed.len() });
+                    if reconnecting && self.config.replay_book_tickers_on_reconnect {
+                        let count = self.cache.replay_book_tickers();
+                        debug!("Replayed {} pre-reconnect book tickers as stale", count);
+                    }
+                    reconnecting = true;
+                    let connected_at = tokio::time::Instant::now();
+                    let mut delay = Duration::from_millis(RECONNECT_DELAY_MS);
+                    let reason = match self.process_messages().await {
+                        Ok(Some(frame)) => {
+                            let code = u16::from(frame.code);
+                            warn!("Server closed connection: code {} ({:?}), reason {:?}", code, frame.code, frame.reason);
+                            self.cache.events.record(
+                                EventKind::Closed,
+                                None,
+                                format!("code {} reason {:?}", code, frame.reason),
+                            );
+                            if self.config.long_backoff_close_codes.contains(&code) {
+                                delay = self.config.close_code_backoff;
+                                warn!("Close code {} configured for long backoff, waiting {:?}", code, delay);
+                            }
+                            format!("closed by server: code {} reason {:?}", code, frame.reason)
+                        }
+                        Ok(None) => "connection ended".to_string(),
+                        Err(e @ WebSocketError::MessageTooLarge { .. }) => {
+                            warn!("Dropping connection: {}", e);
+                            self.cache.events.record(EventKind::ParseError, None, e.to_string());
+                            e.to_string()
+                        }
+                        Err(e) => {
+                            error!("Error processing messages: {}", e);
+                            e.to_string()
+                        }
+                    };
+                    self.cache.publish(MarketEvent::Disconnected { reason });
+                    let uptime = connected_at.elapsed();
+                    // A connection that stayed up long enough proves the config works
+                    if uptime >= self.config.stable_connection_period {
+                        self.cache.reconnect_attempts.store(0, AtomicOrdering::Relaxed);
+                    }
+                    // A requested reconnect isn't a failure and needs no backoff
+                    if let Some(preserve_books) = self.cache.take_reconnect_request() {
+                        self.preserve_books_on_connect = preserve_books;
+                        delay = Duration::ZERO;
+                    } else {
+                        self.register_failure(started_at);
+                    }
+                    self.cache.total_reconnects.fetch_add(1, AtomicOrdering::Relaxed);
+                    let attempts = self.cache.reconnect_attempts();
+                    // Uptime tells flapping connections apart from long-lived ones that dropped
+                    warn!(
+                        "Reconnecting attempt {}/{}, previous connection was up for {:.1?}",
+                        attempts, MAX_RECONNECT_ATTEMPTS, uptime
+                    );
+                    self.cache.events.record(
+                        EventKind::Reconnect,
+                        None,
+                        format!("attempt {}/{} after {:.1?} up", attempts, MAX_RECONNECT_ATTEMPTS, uptime),
+                    );
+                    self.cache.publish(MarketEvent::Reconnecting { attempt: attempts, delay });
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    self.register_failure(started_at);
+                    self.cache.total_reconnects.fetch_add(1, AtomicOrdering::Relaxed);
+                    self.cache.events.record(
+                        EventKind::Reconnect,
+                        None,
+                        format!("connection failed: {}", e),
+                    );
+                    let delay = Duration::from_millis(RECONNECT_DELAY_MS);
+                    self.cache.publish(MarketEvent::Reconnecting { attempt: self.cache.reconnect_attempts(), delay });
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    fn get_book_ticker<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<BookTickerData> {
+        self.cache.book_ticker(symbol)
+    }
+
+    #[allow(dead_code)]
+    fn get_last_trade<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<TradeData> {
+        self.cache.last_trade(symbol)
+    }
+
+    #[allow(dead_code)]
+    fn recent_trades<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Vec<TradeData> {
+        self.cache.recent_trades(symbol)
+    }
+
+    #[allow(dead_code)]
+    fn quoted_trades<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Vec<QuotedTrade> {
+        self.cache.quoted_trades(symbol)
+    }
+
+    #[allow(dead_code)]
+    fn realized_volatility<'a>(&self, symbol: impl Into<MarketSymbol<'a>>, window: Duration) -> Option<f64> {
+        self.cache.realized_volatility(symbol, window)
+    }
+
+    #[allow(dead_code)]
+    fn get_mark_price<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<MarkPriceData> {
+        self.cache.mark_price(symbol)
+    }
+
+    #[allow(dead_code)]
+    fn index_price(&self, symbol: &str) -> Option<Decimal> {
+        self.get_mark_price(symbol).map(|m| m.index_price)
+    }
+
+    #[allow(dead_code)]
+    fn estimated_settle_price(&self, symbol: &str) -> Option<Decimal> {
+        self.get_mark_price(symbol).map(|m| m.estimated_settle_price)
+    }
+
+    // Unix ms of the next funding event; None without a mark price or for
+    // contracts that don't fund
+    #[allow(dead_code)]
+    fn next_funding_time(&self, symbol: &str) -> Option<u64> {
+        self.get_mark_price(symbol).map(|m| m.next_funding_time).filter(|t| *t != 0)
+    }
+
+    #[allow(dead_code)]
+    fn get_order_book<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<OrderBook> {
+        self.cache.with_order_book(symbol, OrderBook::clone)
+    }
+
+    // Resting quantity at one price level of `side`, read under the book's guard
+    #[allow(dead_code)]
+    fn quantity_at<'a>(&self, symbol: impl Into<MarketSymbol<'a>>, side: BookSide, price: Decimal) -> Option<Decimal> {
+        self.cache.with_order_book(symbol, |book| book.quantity_at(side, price))
+    }
+
+    // Top N buckets per side of a symbol's grouped view (see group_tick)
+    #[allow(dead_code)]
+    fn get_grouped_book<'a>(&self, symbol: impl Into<MarketSymbol<'a>>, n: usize) -> Option<GroupedBook> {
+        self.cache.with_order_book(symbol, |book| book.grouped_levels(n))?
+    }
+
+    // HSET-ready fields for a symbol's book; see OrderBook::to_redis_fields
+    #[allow(dead_code)]
+    fn book_redis_fields<'a>(&self, symbol: impl Into<MarketSymbol<'a>>, levels: usize) -> Option<(String, Vec<(String, String)>)> {
+        self.cache.with_order_book(symbol, |book| (book.redis_key(), book.to_redis_fields(levels)))
+    }
+
+    #[allow(dead_code)]
+    fn recent_events(&self) -> Vec<OperationalEvent> {
+        self.cache.recent_events()
+    }
+
+    #[allow(dead_code)]
+    fn subscribe(&self) -> broadcast::Receiver<MarketEvent> {
+        self.cache.subscribe()
+    }
+
+    // Per-symbol 1-second trade aggregates; the bucketizer stops when the receiver is dropped
+    #[allow(dead_code)]
+    fn trade_buckets(&self) -> tokio::sync::mpsc::Receiver<trade_bucketizer::TradeBucket> {
+        let (tx, rx) = tokio::sync::mpsc::channel(TRADE_BUCKET_CHANNEL_CAPACITY);
+        let bucketizer = trade_bucketizer::TradeBucketizer::new(tx);
+        tokio::spawn(bucketizer.run(self.cache.subscribe()).in_current_span());
+        rx
+    }
+
+    #[allow(dead_code)]
+    fn top_of_book<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<TopOfBook> {
+        self.cache.top_of_book(symbol)
+    }
+
+    #[allow(dead_code)]
+    fn market_state<'a>(&self, symbol: impl Into<MarketSymbol<'a>>, levels: usize) -> Option<MarketState> {
+        self.cache.market_state(symbol, levels)
+    }
+
+    // Symbols whose depth book was downgraded to partial depth (depth_downgrade_after)
+    #[allow(dead_code)]
+    fn depth_downgraded_symbols(&self) -> Vec<String> {
+        self.cache.depth_downgraded.iter().map(|symbol| symbol.clone()).collect()
+    }
+
+    #[allow(dead_code)]
+    fn book_age<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<Duration> {
+        self.cache.book_age(symbol)
+    }
+
+    // (current, peak) levels held by a book, for sizing compaction and memory
+    #[allow(dead_code)]
+    fn book_levels<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<(usize, usize)> {
+        self.cache.book_levels(symbol)
+    }
+
+    #[allow(dead_code)]
+    fn quote<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<Quote> {
+        self.cache.quote(symbol)
+    }
+
+    #[allow(dead_code)]
+    fn spread_in_ticks<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<u64> {
+        self.cache.spread_in_ticks(symbol)
+    }
+
+    #[allow(dead_code)]
+    fn spread_exceeds<'a>(&self, symbol: impl Into<MarketSymbol<'a>>, bps: Decimal) -> Option<bool> {
+        self.cache.spread_exceeds(symbol, bps)
+    }
+
+    #[allow(dead_code)]
+    fn is_thin(&self, symbol: &str, factor: Decimal) -> bool {
+        self.cache.is_thin(symbol, factor)
+    }
+
+    // Needs ofi_window_ms
+    #[allow(dead_code)]
+    fn order_flow_imbalance(&self, symbol: &str) -> Option<Decimal> {
+        self.cache.order_flow_imbalance(symbol)
+    }
+
+    // Needs spread_sample_interval_ms; the window is capped by spread_history_ms
+    #[allow(dead_code)]
+    fn time_weighted_spread(&self, symbol: &str, window: Duration) -> Option<Decimal> {
+        self.cache.time_weighted_spread(symbol, window)
+    }
+
+    #[allow(dead_code)]
+    fn resync_durations(&self, symbol: &str) -> Option<DurationHistogram> {
+        self.cache.resync_durations(symbol)
+    }
+
+    // Streams of the current (or last) connection
+    #[allow(dead_code)]
+    fn active_streams(&self) -> &[String] {
+        &self.active_streams
+    }
+
+    // Connect (and re-snapshot) only while holding a slot of `limiter`; give
+    // every client of a sharded setup a clone of the same limiter before run()
+    #[allow(dead_code)]
+    fn share_connect_limiter(&mut self, limiter: ConnectLimiter) {
+        self.connect_limiter = Some(limiter);
     }
 
-    async fn initialize_order_books(&self) -> Result<(), WebSocketError> {
-        for symbol in &self.symbols {
-            let snapshot = self.fetch_order_book_snapshot(symbol).await?;
-            match self.cache.order_books.entry(symbol.to_uppercase()) {
-                dashmap::mapref::entry::Entry::Occupied(mut entry) => {
-                    entry.get_mut().apply_snapshot(snapshot);
-                }
-                dashmap::mapref::entry::Entry::Vacant(entry) => {
-                    let mut book = OrderBook::new(symbol.to_uppercase());
-                    book.apply_snapshot(snapshot);
-                    entry.insert(book);
-                }
-            }
-            info!("Initialized order book for {}", symbol);
-            
-            // Avoid rate limiting
-            tokio::time::sleep(Duration::from_millis(500)).await;
+    // Keep this client's market data in `other`'s store, so either client can
+    // read both markets with (Market, symbol) arguments. Call before run() and
+    // subscribe(): the cache is rebuilt around the shared store.
+    #[allow(dead_code)]
+    fn share_market_store(&mut self, other: &BinanceWebSocketClient) -> Result<(), WebSocketError> {
+        let store = other.cache.market_store();
+        if store.markets.contains(&self.config.market) {
+            return Err(WebSocketError::ConfigError(format!(
+                "market store already holds {:?} data from another client",
+                self.config.market
+            )));
         }
+        self.cache = Arc::new(MarketDataCache::with_store(&self.config, store));
         Ok(())
     }
 
-    #[instrument(skip(self))]
-    async fn connect(&mut self) -> Result<(), WebSocketError> {
-        // First initialize order books with snapshots
-        self.initialize_order_books().await?;
+    // Failures counted toward the reconnect limit, and reconnects since startup
+    #[allow(dead_code)]
+    fn reconnect_stats(&self) -> (u8, u64) {
+        (self.cache.reconnect_attempts(), self.cache.total_reconnects())
+    }
 
-        let streams = self.symbols
-            .iter()
-            .flat_map(|symbol| {
-                let s = symbol.to_lowercase();
-                vec![
-                    format!("{}@bookTicker", s),
-                    format!("{}@trade", s),
-                    format!("{}@depth", s), // Add depth stream
-                ]
-            })
-            .collect::<Vec<_>>()
-            .join("/");
+    // Received bytes and messages per second over the last sample interval
+    #[allow(dead_code)]
+    fn ingress_rate(&self) -> IngressRate {
+        self.cache.ingress_rate()
+    }
 
-        let ws_url = format!("{}?streams={}", BINANCE_WS_URL, streams);
-        let url = Url::parse(&ws_url)?;
-        
-        let (ws_stream, _) = connect_async(url).await?;
-        self.ws_stream = Some(ws_stream);
-        self.reconnect_attempts = 0;
-        Ok(())
+    // Best bid or ask price changes per second, over the last second
+    #[allow(dead_code)]
+    fn flicker_rate(&self, symbol: &str) -> Option<f64> {
+        self.cache.flicker_rate(symbol)
     }
 
-    #[instrument(skip(self))]
-    async fn process_messages(&mut self) -> Result<(), WebSocketError> {
-        let mut book_ticker_batch = Vec::with_capacity(BATCH_SIZE);
-        let mut trade_batch = Vec::with_capacity(BATCH_SIZE);
-        let mut last_flush = tokio::time::Instant::now();
-        
-        let (write, mut read) = self.ws_stream.as_mut().unwrap().split();
-        let write = Arc::new(tokio::sync::Mutex::new(write));
+    // Books changing their best price more than `rate` times per second, fastest first
+    #[allow(dead_code)]
+    fn flickering_books(&self, rate: f64) -> Vec<(String, f64)> {
+        self.cache.flickering_books(rate)
+    }
 
-        loop {
-            tokio::select! {
-                _ = flush_batch(
-                    &mut book_ticker_batch,
-                    &mut trade_batch,
-                    &mut last_flush,
-                    self.cache.clone(),
-                ) => {}
+    // Fraction of depth updates accepted over the last ingress sample interval
+    #[allow(dead_code)]
+    fn acceptance_rate(&self, symbol: &str) -> Option<f64> {
+        self.cache.acceptance_rate(symbol)
+    }
 
-                msg = read.next() => {
-                    let msg = match msg.transpose()? {
-                        Some(m) => m,
-                        None => break Ok(()),
-                    };
+    // Depth updates accepted and rejected since startup
+    #[allow(dead_code)]
+    fn update_counts<'a>(&self, symbol: impl Into<MarketSymbol<'a>>) -> Option<(u64, u64)> {
+        self.cache.update_counts(symbol)
+    }
 
-                    match msg {
-                        Message::Text(text) => handle_message(
-                            &text,
-                            &mut book_ticker_batch,
-                            &mut trade_batch,
-                            BATCH_SIZE,
-                            self.cache.clone(),
-                        ).await?,
-                        Message::Ping(data) => {
-                            write.lock().await.send(Message::Pong(data)).await?;
-                        }
-                        Message::Close(_) => {
-                            debug!("Received close frame");
-                            break Ok(());
-                        }
-                        _ => {}
-                    }
-                }
-            }
-        }
+    // Frames dropped because they weren't a parseable stream message
+    #[allow(dead_code)]
+    fn invalid_frames(&self) -> u64 {
+        self.cache.invalid_frames()
     }
 
-    #[instrument(skip(self))]
-    async fn run(&mut self) -> Result<(), WebSocketError> {
-        loop {
-            if self.reconnect_attempts >= MAX_RECONNECT_ATTEMPTS {
-                return Err(WebSocketError::MaxReconnectAttempts);
-            }
+    // Trades dropped as already seen; always 0 without dedup_trades
+    #[allow(dead_code)]
+    fn duplicate_trades(&self) -> u64 {
+        self.cache.duplicate_trades()
+    }
 
-            match self.connect().await {
-                Ok(_) => {
-                    info!("Connected to Binance WebSocket");
-                    if let Err(e) = self.process_messages().await {
-                        error!("Error processing messages: {}", e);
-                    }
-                    self.reconnect_attempts += 1;
-                    warn!("Reconnecting attempt {}/{}", self.reconnect_attempts, MAX_RECONNECT_ATTEMPTS);
-                    tokio::time::sleep(tokio::time::Duration::from_millis(RECONNECT_DELAY_MS)).await;
-                }
-                Err(e) => {
-                    error!("Connection failed: {}", e);
-                    self.reconnect_attempts += 1;
-                    tokio::time::sleep(tokio::time::Duration::from_millis(RECONNECT_DELAY_MS)).await;
-                }
-            }
-        }
+    // Frames per unknown or unhandled stream type, counted under every unknown_stream_policy
+    #[allow(dead_code)]
+    fn unknown_stream_counts(&self) -> Vec<(String, u64)> {
+        self.cache.unknown_stream_counts()
     }
 
     #[allow(dead_code)]
-    fn get_book_ticker(&self, symbol: &str) -> Option<BookTickerData> {
-        let symbol = symbol.to_uppercase();
-        self.cache.book_tickers
-            .get(&symbol)
-            .map(|r| r.value().clone())
+    fn force_reconnect(&self, preserve_books: bool) {
+        self.cache.force_reconnect(preserve_books);
     }
 
     #[allow(dead_code)]
-    fn get_last_trade(&self, symbol: &str) -> Option<TradeData> {
-        let symbol = symbol.to_uppercase();
-        self.cache.last_trades
-            .get(&symbol)
-            .map(|r| r.value().clone())
+    fn enable_symbol_debug(&self, symbol: &str) {
+        self.cache.enable_symbol_debug(symbol);
     }
 
     #[allow(dead_code)]
-    fn get_order_book(&self, symbol: &str) -> Option<OrderBook> {
-        let symbol = symbol.to_uppercase();
-        self.cache.order_books
-            .get(&symbol)
-            .map(|r| r.value().clone())
+    fn disable_symbol_debug(&self, symbol: &str) {
+        self.cache.disable_symbol_debug(symbol);
+    }
+
+    #[allow(dead_code)]
+    fn pause(&self) {
+        self.cache.pause();
+    }
+
+    #[allow(dead_code)]
+    fn resume(&self) {
+        self.cache.resume();
+    }
+}
+
+// The individual certificates in a PEM file, each with its BEGIN/END lines
+fn load_pem_certificates(path: &std::path::Path) -> Result<Vec<String>, WebSocketError> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let text = std::fs::read_to_string(path)?;
+    let pems: Vec<String> = text
+        .split_inclusive(END)
+        .filter(|block| block.contains(END))
+        .map(|block| block.trim_start().to_string())
+        .collect();
+    if pems.is_empty() {
+        return Err(WebSocketError::ConfigError(format!("no PEM certificates in {}", path.display())));
+    }
+    Ok(pems)
+}
+
+// Drop symbols that repeat an earlier one case-insensitively, keeping the first;
+// duplicates would subscribe the same streams twice
+fn dedup_symbols(symbols: Vec<String>) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+    let (unique, dropped): (Vec<_>, Vec<_>) = symbols
+        .into_iter()
+        .partition(|symbol| seen.insert(symbol.to_uppercase()));
+    if !dropped.is_empty() {
+        warn!("Ignoring duplicate symbols: {}", dropped.join(", "));
+    }
+    unique
+}
+
+async fn fetch_snapshot(
+    http_client: &HttpClient,
+    config: &ClientConfig,
+    symbol: &str,
+) -> Result<OrderBookSnapshot, WebSocketError> {
+    let url = format!(
+        "{}{}?symbol={}&limit={}",
+        config.api_url(), config.market.depth_path(), symbol.to_uppercase(), config.snapshot_depth_for(symbol)
+    );
+    
+    debug!("Fetching order book snapshot for {}", symbol);
+    let response = http_client.get(&url).send().await?;
+    let status = response.status();
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.as_u16() == 418 {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(if status.as_u16() == 418 { config.ip_ban_backoff } else { config.rate_limit_backoff });
+        // Hold this fetch slot for the whole backoff; retrying early is what turns
+        // a 429 into a 418 ban
+        error!("REST API returned {} for {} snapshot, backing off for {:?}", status, symbol, retry_after);
+        tokio::time::sleep(retry_after).await;
+        return Err(WebSocketError::RateLimited { status: status.as_u16(), retry_after });
     }
+    let response = response.error_for_status()?;
+    let used_weight = response
+        .headers()
+        .get("x-mbx-used-weight-1m")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u32>().ok());
+    let body = response.text().await?;
+    // Weight is counted per calendar minute; near the limit, wait for the
+    // window to reset rather than risk a 429 and an IP ban
+    if let Some(used) = used_weight.filter(|w| *w >= REST_WEIGHT_BACKOFF_1M) {
+        let wait = Duration::from_millis(60_000 - unix_time_ms() % 60_000);
+        warn!("REST used weight {} near limit, pausing snapshot fetches for {:?}", used, wait);
+        tokio::time::sleep(wait).await;
+    }
+    let snapshot: OrderBookSnapshot = serde_json::from_str(&body)?;
+    
+    Ok(snapshot)
 }
 
 #[instrument(skip_all)]
 async fn handle_message(
     text: &str,
+    received_at_ms: Option<u64>,
     book_batch: &mut Vec<BookTickerData>,
     trade_batch: &mut Vec<TradeData>,
     batch_size: usize,
     cache: Arc<MarketDataCache>,
 ) -> Result<(), WebSocketError> {
-    if let Ok(msg) = serde_json::from_str::<StreamMessage>(text) {
-        let parts: Vec<&str> = msg.stream.split('@').collect();
-        if parts.len() != 2 {
-            return Ok(());
+    let combined;
+    let (stream, data) = match cache.raw_stream.as_deref() {
+        // Raw /ws/ frames are the bare payload of the one configured stream
+        Some(stream) => match serde_json::from_str::<serde_json::Value>(text) {
+            Ok(data) => (stream, data),
+            Err(e) => {
+                cache.record_invalid_frame(text, &e);
+                return Ok(());
+            }
+        },
+        None => match serde_json::from_str::<StreamMessage>(text) {
+            Ok(msg) => {
+                combined = msg.stream;
+                (combined.as_str(), msg.data)
+            }
+            Err(e) => {
+                cache.record_invalid_frame(text, &e);
+                return Ok(());
+            }
+        },
+    };
+
+    // All-market streams ("!bookTicker", "!markPrice@arr") carry every
+    // symbol; only allowlisted ones make it into the cache
+    if let Some(stream_name) = stream.strip_prefix('!') {
+        let Ok(stream_type) = stream_name.parse::<StreamType>() else {
+            return cache.note_unknown_stream("Unknown", stream);
+        };
+        if cache.config.record_inter_arrival {
+            cache.record_arrival(stream);
         }
+        let items = match data {
+            serde_json::Value::Array(items) => items,
+            item => vec![item],
+        };
+        for item in items {
+            let Some(symbol) = item.get("s").and_then(|s| s.as_str()).map(str::to_lowercase) else {
+                continue;
+            };
+            if !cache.is_firehose_allowed(&symbol) {
+                continue;
+            }
+            if cache.is_symbol_debug(&symbol) {
+                info!("{} message: {}", stream, item);
+            }
+            route_payload(&symbol, stream_name, stream_type, item, received_at_ms, book_batch, trade_batch, batch_size, &cache)?;
+        }
+        return Ok(());
+    }
+
+    let Some((symbol, stream_name)) = stream.split_once('@') else {
+        return Ok(());
+    };
+    let stream_type = match stream_name.parse::<StreamType>() {
+        Ok(stream_type) => stream_type,
+        Err(_) => return cache.note_unknown_stream("Unknown", stream_name),
+    };
+
+    if cache.config.record_inter_arrival {
+        cache.record_arrival(stream);
+    }
+    if cache.is_symbol_debug(symbol) {
+        info!("{} message: {}", stream, data);
+    }
 
-        match parts[1] {
-            "bookTicker" => {
-                if let Ok(ticker) = serde_json::from_value::<BookTickerData>(msg.data) {
+    route_payload(symbol, stream_name, stream_type, data, received_at_ms, book_batch, trade_batch, batch_size, &cache)
+}
+
+// The "stream" value of a combined-stream frame, found by scanning so it can
+// still be read from a truncated frame. Binance sends it before "data".
+fn frame_stream_name(text: &str) -> Option<&str> {
+    let start = text.find(r#""stream":""#)? + r#""stream":""#.len();
+    let len = text[start..].find('"')?;
+    Some(&text[start..start + len])
+}
+
+// The first "E" (event time, ms) in a frame. Array payloads such as
+// "!markPrice@arr" share one event time, so the first is representative.
+fn frame_event_time(text: &str) -> Option<u64> {
+    let start = text.find(r#""E":"#)? + r#""E":"#.len();
+    let digits = text[start..].trim_start();
+    let len = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len());
+    digits[..len].parse().ok()
+}
+
+// Parse one stream payload and hand it to the cache (or the batches)
+#[allow(clippy::too_many_arguments)]
+fn route_payload(
+    symbol: &str,
+    stream_name: &str,
+    stream_type: StreamType,
+    data: serde_json::Value,
+    received_at_ms: Option<u64>,
+    book_batch: &mut Vec<BookTickerData>,
+    trade_batch: &mut Vec<TradeData>,
+    batch_size: usize,
+    cache: &MarketDataCache,
+) -> Result<(), WebSocketError> {
+    match stream_type {
+        StreamType::BookTicker => {
+            match serde_json::from_value::<BookTickerData>(data) {
+                Ok(mut ticker) => {
+                    ticker.received_at_ms = received_at_ms;
                     book_batch.push(ticker);
                     if book_batch.len() >= batch_size {
                         debug!("Batch limit reached for book tickers");
                     }
                 }
+                Err(e) => {
+                    warn!("Failed to parse book ticker for {}: {}", symbol, e);
+                    cache.events.record(EventKind::ParseError, Some(&symbol.to_uppercase()), format!("bookTicker: {}", e));
+                }
             }
-            "trade" => {
-                match serde_json::from_value::<TradeData>(msg.data) {
-                    Ok(trade) => {
-                        trade_batch.push(trade);
-                        if trade_batch.len() >= batch_size {
-                            debug!("Trade batch ready for flushing");
-                        }
+        }
+        StreamType::Trade => {
+            match serde_json::from_value::<TradeData>(data) {
+                Ok(mut trade) => {
+                    trade.received_at_ms = received_at_ms;
+                    trade_batch.push(trade);
+                    if trade_batch.len() >= batch_size {
+                        debug!("Trade batch ready for flushing");
                     }
-                    Err(e) => warn!("Failed to parse trade: {}", e),
+                }
+                Err(e) => {
+                    warn!("Failed to parse trade for {}: {}", symbol, e);
+                    cache.events.record(EventKind::ParseError, Some(&symbol.to_uppercase()), format!("trade: {}", e));
                 }
             }
-            "depth" => {
-                match serde_json::from_value::<DepthUpdateData>(msg.data) {
-                    Ok(depth_update) => {
-                        // Process depth update immediately rather than batching
-                        cache.update_order_book(depth_update);
-                    }
-                    Err(e) => warn!("Failed to parse depth update: {}", e),
+        }
+        StreamType::Depth => {
+            match serde_json::from_value::<DepthUpdateData>(data) {
+                Ok(depth_update) => {
+                    // Process depth update immediately rather than batching
+                    cache.update_order_book(cache.order_book_key(symbol, stream_name), depth_update);
+                }
+                Err(e) => {
+                    warn!("Failed to parse depth update for {}: {}", symbol, e);
+                    cache.events.record(EventKind::ParseError, Some(&symbol.to_uppercase()), format!("depth: {}", e));
+                    cache.invalidate_book(&cache.order_book_key(symbol, stream_name), "unparseable depth update");
+                }
+            }
+        }
+        StreamType::MarkPrice => {
+            match serde_json::from_value::<MarkPriceData>(data) {
+                Ok(mark_price) => cache.update_mark_price(mark_price),
+                Err(e) => {
+                    warn!("Failed to parse mark price for {}: {}", symbol, e);
+                    cache.events.record(EventKind::ParseError, Some(&symbol.to_uppercase()), format!("markPrice: {}", e));
+                }
+            }
+        }
+        StreamType::PartialDepth(_) if cache.is_depth_downgraded(symbol) => {
+            match serde_json::from_value::<DepthUpdateData>(data) {
+                Ok(partial) => cache.apply_partial_depth(symbol, partial),
+                Err(e) => {
+                    warn!("Failed to parse partial depth for {}: {}", symbol, e);
+                    cache.events.record(EventKind::ParseError, Some(&symbol.to_uppercase()), format!("partial depth: {}", e));
                 }
             }
-            _ => warn!("Unknown stream type: {}", parts[1]),
         }
+        StreamType::AggTrade
+        | StreamType::PartialDepth(_)
+        | StreamType::Kline
+        | StreamType::ForceOrder
+        | StreamType::Ticker
+        | StreamType::MiniTicker => return cache.note_unknown_stream("Unhandled", stream_name),
     }
     Ok(())
 }
@@ -545,11 +5478,13 @@ async fn flush_batch(
     last_flush: &mut tokio::time::Instant,
     cache: Arc<MarketDataCache>,
 ) {
-    let mut interval = tokio::time::interval(Duration::from_millis(BATCH_INTERVAL_MS));
+    let batch_size = cache.config.batch_size;
+    let batch_interval = cache.config.batch_interval;
+    let mut interval = tokio::time::interval(batch_interval);
     loop {
         interval.tick().await;
-        if book_batch.len() >= BATCH_SIZE || trade_batch.len() >= BATCH_SIZE 
-            || last_flush.elapsed() >= Duration::from_millis(BATCH_INTERVAL_MS)
+        if book_batch.len() >= batch_size || trade_batch.len() >= batch_size 
+            || last_flush.elapsed() >= batch_interval
         {
             if !book_batch.is_empty() {
                 let count = book_batch.len();
@@ -566,42 +5501,356 @@ async fn flush_batch(
     }
 }
 
+// End-to-end check of the sync state machine: maintain a book from snapshot +
+// stream for a short window, then diff it against a fresh REST snapshot.
+// Returns true when the top levels match within tolerance.
+async fn verify_book_sync(mut config: ClientConfig, symbol: &str) -> Result<bool, WebSocketError> {
+    let symbol = symbol.to_uppercase();
+    config.symbols = vec![symbol.clone()];
+    let mut client = BinanceWebSocketClient::with_config(config.clone())?;
+    let cache = client.cache.clone();
+    let http_client = client.http_client.clone();
+    let span = client.instance_span();
+    let task = tokio::spawn(async move { client.run().await }.instrument(span));
+
+    info!("Verifying {} order book sync over {}s", symbol, VERIFY_WINDOW_SECS);
+    tokio::time::sleep(Duration::from_secs(VERIFY_WINDOW_SECS)).await;
+
+    let key = cache.key(&symbol);
+    let synced = cache.store.order_books.get(&key).map(|b| b.synced).unwrap_or(false);
+    if !synced {
+        task.abort();
+        println!("{}: FAIL - book never synced", symbol);
+        return Ok(false);
+    }
+
+    // Let the maintained book catch up to the reference snapshot, then freeze it
+    let reference = fetch_snapshot(&http_client, &config, &symbol).await?;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(VERIFY_CATCH_UP_SECS);
+    loop {
+        let caught_up = cache.store.order_books
+            .get(&key)
+            .map(|b| b.last_update_id >= reference.last_update_id)
+            .unwrap_or(false);
+        if caught_up || tokio::time::Instant::now() >= deadline {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    cache.pause();
+    task.abort();
+
+    let Some(book) = cache.store.order_books.get(&key).map(|r| r.value().clone()) else {
+        println!("{}: FAIL - book missing", symbol);
+        return Ok(false);
+    };
+    let mut expected = OrderBook::new(symbol.clone());
+    expected.apply_snapshot(reference);
+
+    let mut compared = 0usize;
+    let mut mismatched = 0usize;
+    let sides = [
+        ("bid", expected.top_bids(VERIFY_DEPTH), &book.bids),
+        ("ask", expected.top_asks(VERIFY_DEPTH), &book.asks),
+    ];
+    for (side, levels, maintained) in sides {
+        for (price, qty) in levels {
+            compared += 1;
+            let actual = maintained.get(&price).copied().unwrap_or_default();
+            if actual != qty {
+                mismatched += 1;
+                println!("  {} {}: snapshot qty {}, maintained qty {}", side, price, qty, actual);
+            }
+        }
+    }
+
+    let ratio = if compared == 0 {
+        Decimal::ONE
+    } else {
+        Decimal::from(mismatched) / Decimal::from(compared)
+    };
+    let ok = compared > 0 && ratio <= Decimal::new(VERIFY_MAX_MISMATCH_PCT, 2);
+    println!(
+        "{}: {} - {}/{} top levels mismatched (book u={}, snapshot u={})",
+        symbol,
+        if ok { "OK" } else { "FAIL" },
+        mismatched,
+        compared,
+        book.last_update_id,
+        expected.last_update_id
+    );
+    Ok(ok)
+}
+
+// Offline driver: apply a captured REST snapshot (JSON) and a file of recorded
+// depth updates (one JSON DepthUpdateData per line) in order, the same way the
+// live client does, and report the final book. Returns true if no update was
+// rejected.
+fn replay_book(snapshot_path: &std::path::Path, updates_path: &std::path::Path) -> Result<bool, WebSocketError> {
+    use std::io::BufRead;
+
+    let snapshot: OrderBookSnapshot = serde_json::from_slice(&std::fs::read(snapshot_path)?)?;
+    let updates = std::io::BufReader::new(std::fs::File::open(updates_path)?);
+
+    // The snapshot carries no symbol, so the book is named after the first update
+    let mut book = OrderBook::new(String::new());
+    book.apply_snapshot(snapshot);
+    let (mut applied, mut rejected, mut unparseable) = (0usize, 0usize, 0usize);
+    for (index, line) in updates.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let update: DepthUpdateData = match serde_json::from_str(&line) {
+            Ok(update) => update,
+            Err(e) => {
+                warn!("Line {}: unparseable depth update, skipping: {}", index + 1, e);
+                unparseable += 1;
+                continue;
+            }
+        };
+        if book.symbol.is_empty() {
+            book.symbol = update.symbol.to_uppercase();
+        }
+        match book.apply_update(&update) {
+            Ok(()) => applied += 1,
+            Err(e) => {
+                warn!("Line {}: {} rejected update: {}", index + 1, book.symbol, e);
+                // Mirror the live client: a rejected update leaves the book needing a resync
+                book.synced = false;
+                rejected += 1;
+            }
+        }
+    }
+
+    println!(
+        "{}: {} updates applied, {} rejected, {} unparseable; final u={}, synced={}, {} bids / {} asks",
+        book.symbol, applied, rejected, unparseable, book.last_update_id, book.synced, book.bids.len(), book.asks.len()
+    );
+    for ((bid_price, bid_qty), (ask_price, ask_qty)) in book.top_bids(10).into_iter().zip(book.top_asks(10)) {
+        println!("  {} @ {}  |  {} @ {}", bid_qty, bid_price, ask_qty, ask_price);
+    }
+    Ok(rejected == 0)
+}
+
+// Entry point for the cargo-fuzz target in fuzz/. The first line of `data` is a
+// snapshot and each further line a depth update, all as JSON, fed through the same
+// parse-and-apply path as replay_book. Panics if the applier breaks an invariant
+// it owns. A synced book must not be crossed whenever every bid price in the input
+// sits below every ask price; otherwise crossing can come from the input itself.
+#[cfg(fuzzing)]
+pub fn fuzz_apply_updates(data: &[u8]) {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    let mut lines = text.lines();
+    let Some(Ok(snapshot)) = lines.next().map(serde_json::from_str::<OrderBookSnapshot>) else {
+        return;
+    };
+    let updates: Vec<DepthUpdateData> = lines.filter_map(|line| serde_json::from_str(line).ok()).collect();
+    let prices = |levels: &[[String; 2]]| {
+        levels.iter().filter_map(|[price, _]| Decimal::from_str_exact(price).ok()).collect::<Vec<_>>()
+    };
+    let bid_prices = updates.iter().flat_map(|u| prices(&u.bids)).chain(prices(&snapshot.bids));
+    let ask_prices = updates.iter().flat_map(|u| prices(&u.asks)).chain(prices(&snapshot.asks));
+    let uncrossable = match (bid_prices.max(), ask_prices.min()) {
+        (Some(max_bid), Some(min_ask)) => max_bid < min_ask,
+        _ => true,
+    };
+    let mut book = OrderBook::new("FUZZ".to_string());
+    book.group_tick = Some(Decimal::new(5, 1));
+    // Dust filtering on, so replicas (which don't filter) are checked against it
+    book.min_qty = Decimal::new(1, 3);
+    book.apply_snapshot(snapshot);
+    // Consumer-side replica fed the same snapshot and delta events a subscriber would see
+    let mut replica: Option<OrderBook> = None;
+    for update in updates {
+        let (was_synced, before_id, before_bids, before_asks) =
+            (book.synced, book.last_update_id, book.bids.clone(), book.asks.clone());
+        match book.apply_update(&update) {
+            Ok(()) => {
+                assert!(book.synced, "accepted update left the book unsynced");
+                assert_eq!(book.last_update_id, update.final_update_id);
+                if !was_synced {
+                    let event = OrderBookUpdateEvent::from_book(&book, usize::MAX);
+                    replica = Some(OrderBook::from_snapshot_event(&event));
+                } else if let Some(replica) = replica.as_mut() {
+                    let delta = OrderBookDeltaEvent::from_update(&book, &update);
+                    assert_eq!(replica.apply_delta(&delta), Ok(()));
+                    assert!(replica.bids == book.bids && replica.asks == book.asks, "delta replica diverged");
+                }
+                if let (true, Some((bid, _)), Some((ask, _))) = (uncrossable, book.best_bid(), book.best_ask()) {
+                    assert!(bid < ask, "synced book crossed: bid {} >= ask {}", bid, ask);
+                }
+            }
+            Err(_) => {
+                // A rejected update must leave the book exactly as it was
+                assert_eq!(book.last_update_id, before_id);
+                assert!(book.bids == before_bids && book.asks == before_asks, "rejected update modified levels");
+                book.synced = false;
+            }
+        }
+        assert!(
+            book.bids.values().chain(book.asks.values()).all(|qty| !book.is_removed_qty(*qty)),
+            "zero or dust quantity level stored"
+        );
+        // The incrementally maintained grouped view must match a full rebuild
+        let mut rebuilt = book.clone();
+        rebuilt.rebuild_grouped();
+        assert!(
+            book.grouped_bids == rebuilt.grouped_bids && book.grouped_asks == rebuilt.grouped_asks,
+            "grouped view drifted from the levels"
+        );
+    }
+}
+
+// Whatever has to outlive the subscriber; flushes exported spans when dropped
+struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    _otel: Option<otel::OtelGuard>,
+}
+
+// Log to stdout, filtered by RUST_LOG (default info). With the `otel` feature
+// the same spans are also exported over OTLP when an endpoint is configured.
+fn init_tracing() -> Result<TelemetryGuard, WebSocketError> {
+    let registry = tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(fmt::layer());
+    #[cfg(feature = "otel")]
+    {
+        let (layer, guard) = otel::layer()?.unzip();
+        registry.with(layer).init();
+        Ok(TelemetryGuard { _otel: guard })
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        Ok(TelemetryGuard {})
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), WebSocketError> {
-    fmt()
-        .with_env_filter(
-            EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| EnvFilter::new("info"))
-        )
-        .init();
+    // Held until main returns so exported spans are flushed on exit
+    let _telemetry = init_tracing()?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // --verify SYMBOL [CONFIG]: self-test the book sync and exit nonzero on mismatch
+    if args.first().map(String::as_str) == Some("--verify") {
+        let Some(symbol) = args.get(1) else {
+            return Err(WebSocketError::ConfigError("usage: --verify SYMBOL [CONFIG]".into()));
+        };
+        let config = match args.get(2) {
+            Some(path) => ClientConfig::from_file(path)?,
+            None => ClientConfig::default(),
+        };
+        let ok = verify_book_sync(config, symbol).await?;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    // --replay SNAPSHOT UPDATES: rebuild a book offline from captured data
+    if args.first().map(String::as_str) == Some("--replay") {
+        let (Some(snapshot_path), Some(updates_path)) = (args.get(1), args.get(2)) else {
+            return Err(WebSocketError::ConfigError("usage: --replay SNAPSHOT UPDATES".into()));
+        };
+        let ok = replay_book(snapshot_path.as_ref(), updates_path.as_ref())?;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    // --replay-timed FRAMES [SPEED] [CONFIG]: replay a recorded frame log at
+    // SPEED times real time ("max" for as fast as possible, default 1)
+    if args.first().map(String::as_str) == Some("--replay-timed") {
+        let Some(frames_path) = args.get(1) else {
+            return Err(WebSocketError::ConfigError("usage: --replay-timed FRAMES [SPEED] [CONFIG]".into()));
+        };
+        let speed = match args.get(2).map(String::as_str) {
+            None => 1.0,
+            Some("max") => f64::INFINITY,
+            Some(speed) => speed
+                .parse()
+                .map_err(|_| WebSocketError::ConfigError(format!("invalid replay speed: {}", speed)))?,
+        };
+        let config = match args.get(3) {
+            Some(path) => ClientConfig::from_file(path)?,
+            None => ClientConfig::default(),
+        };
+        let client = BinanceWebSocketClient::with_config(config)?;
+        let frames = std::io::BufReader::new(std::fs::File::open(frames_path)?);
+        let started = tokio::time::Instant::now();
+        let replayed = client.replay_timed(frames, speed).await?;
+        println!(
+            "{} frames replayed in {:.3}s, {} invalid",
+            replayed,
+            started.elapsed().as_secs_f64(),
+            client.cache.invalid_frames()
+        );
+        return Ok(());
+    }
 
-    let symbols = vec!["BTCUSDT".into(), "ETHUSDT".into()];
-    let mut client = BinanceWebSocketClient::new(symbols);
+    // Optional config file path as the first argument
+    let mut client = match args.first() {
+        Some(path) => {
+            let config = ClientConfig::from_file(path)?;
+            info!("Loaded config from {}", path);
+            BinanceWebSocketClient::with_config(config)?
+        }
+        None => {
+            let symbols = vec!["BTCUSDT".into(), "ETHUSDT".into()];
+            BinanceWebSocketClient::new(symbols)?
+        }
+    };
     let cache_clone = client.cache.clone();
+    let span = client.instance_span();
 
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(Duration::from_secs(5));
         loop {
             interval.tick().await;
             info!(
-                "Cache stats: BookTickers={}, Trades={}, OrderBooks={}",
-                cache_clone.book_tickers.len(),
-                cache_clone.last_trades.len(),
-                cache_clone.order_books.len()
+                "Cache stats: BookTickers={}, Trades={}, MarkPrices={}, OrderBooks={}, Reconnects={} (attempt {}/{})",
+                cache_clone.store.book_tickers.len(),
+                cache_clone.store.last_trades.len(),
+                cache_clone.store.mark_prices.len(),
+                cache_clone.store.order_books.len(),
+                cache_clone.total_reconnects(),
+                cache_clone.reconnect_attempts(),
+                MAX_RECONNECT_ATTEMPTS
+            );
+            let rate = cache_clone.ingress_rate();
+            let (messages, bytes) = cache_clone.ingress_totals();
+            info!(
+                "Ingress: {:.0} B/s, {:.0} msg/s ({} bytes, {} messages total, {} invalid)",
+                rate.bytes_per_sec, rate.messages_per_sec, bytes, messages, cache_clone.invalid_frames()
             );
+            for (stream_type, count) in cache_clone.unknown_stream_counts() {
+                info!("Unknown or unhandled stream type {}: {} frames", stream_type, count);
+            }
+
+            for (stream, stats) in cache_clone.inter_arrival_stats() {
+                info!(
+                    "{} inter-arrival: n={}, min={:?}, mean={:?}, p99={:?}, max={:?}",
+                    stream, stats.count, stats.min, stats.mean, stats.p99, stats.max
+                );
+            }
             
             // Print order book statistics
-            for entry in cache_clone.order_books.iter() {
+            for entry in cache_clone.store.order_books.iter() {
                 let book = entry.value();
                 if let (Some((best_bid_price, best_bid_qty)), Some((best_ask_price, best_ask_qty))) = 
                     (book.best_bid(), book.best_ask()) {
                     info!(
-                        "{} Order Book: Best Bid: {} @ {}, Best Ask: {} @ {}, Spread: {}, Synced: {}",
+                        "{} Order Book: Best Bid: {} @ {}, Best Ask: {} @ {}, Spread: {}, Synced: {}, Levels: {} (peak {}), Updates: {} accepted / {} rejected, Ticker divergence (bps): {:?}",
                         book.symbol,
                         best_bid_qty, best_bid_price,
                         best_ask_qty, best_ask_price,
                         best_ask_price - best_bid_price,
-                        book.synced
+                        book.synced,
+                        book.level_count(),
+                        book.peak_levels,
+                        book.accepted_updates,
+                        book.rejected_updates,
+                        book.ticker_divergence_bps
                     );
                 } else {
                     info!(
@@ -611,7 +5860,241 @@ async fn main() -> Result<(), WebSocketError> {
                 }
             }
         }
-    });
+    }.instrument(span.clone()));
+
+    let result = tokio::select! {
+        result = client.run().instrument(span.clone()) => result,
+        _ = tokio::signal::ctrl_c() => {
+            info!("Shutdown signal received");
+            Ok(())
+        }
+    };
+    client.shutdown().instrument(span).await?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str_exact(s).unwrap()
+    }
+
+    fn levels(levels: &[(&str, &str)]) -> Vec<[String; 2]> {
+        levels.iter().map(|(price, qty)| [price.to_string(), qty.to_string()]).collect()
+    }
+
+    fn update(first: u64, last: u64, prev: u64, bids: &[(&str, &str)], asks: &[(&str, &str)]) -> DepthUpdateData {
+        DepthUpdateData {
+            event_type: "depthUpdate".into(),
+            event_time: 0,
+            transaction_time: 0,
+            symbol: "BTCUSDT".into(),
+            first_update_id: first,
+            final_update_id: last,
+            prev_final_update_id: prev,
+            bids: levels(bids),
+            asks: levels(asks),
+        }
+    }
+
+    // Snapshot at lastUpdateId 100, unsynced
+    fn snapshot_book() -> OrderBook {
+        let mut book = OrderBook::new("BTCUSDT".into());
+        book.apply_snapshot(OrderBookSnapshot {
+            last_update_id: 100,
+            bids: levels(&[("100", "1"), ("99", "2"), ("98", "3")]),
+            asks: levels(&[("101", "1"), ("102", "2"), ("103", "3")]),
+        });
+        book
+    }
+
+    // The snapshot book synced by an empty update, at lastUpdateId 105
+    fn synced_book() -> OrderBook {
+        let mut book = snapshot_book();
+        book.apply_update(&update(95, 105, 90, &[], &[])).unwrap();
+        assert!(book.synced);
+        book
+    }
+
+    fn ticker(symbol: &str, update_id: u64, bid: &str) -> BookTickerData {
+        BookTickerData {
+            update_id,
+            symbol: symbol.into(),
+            bid_price: dec(bid),
+            bid_qty: Decimal::ONE,
+            ask_price: dec(bid) + Decimal::ONE,
+            ask_qty: Decimal::ONE,
+            received_at_ms: None,
+        }
+    }
+
+    #[test]
+    fn weighted_imbalance_rejects_decay_outside_unit_interval() {
+        let book = synced_book();
+        assert_eq!(book.weighted_imbalance(Decimal::ZERO, 3), None);
+        assert_eq!(book.weighted_imbalance(dec("-0.5"), 3), None);
+        assert_eq!(book.weighted_imbalance(dec("1.5"), 3), None);
+    }
+
+    #[test]
+    fn weighted_imbalance_weights_levels_by_decay() {
+        let mut book = synced_book();
+        book.apply_update(&update(106, 106, 105, &[], &[("102", "1"), ("103", "1")])).unwrap();
+        // Decay 1 is the plain imbalance: bids 6, asks 3
+        assert_eq!(book.weighted_imbalance(Decimal::ONE, 3), Some(dec("3") / dec("9")));
+        // Bids 1 + 1 + 0.75, asks 1 + 0.5 + 0.25
+        assert_eq!(book.weighted_imbalance(dec("0.5"), 3), Some(dec("1") / dec("4.5")));
+    }
+
+    #[test]
+    fn apply_update_rejects_invalid_level_without_touching_book() {
+        let mut book = synced_book();
+        let before = (book.top_bids(10), book.top_asks(10));
+        let result = book.apply_update(&update(106, 106, 105, &[("100", "5")], &[("abc", "1")]));
+        assert_eq!(
+            result,
+            Err(OrderBookError::InvalidLevel { final_update_id: 106, price: "abc".into(), qty: "1".into() })
+        );
+        assert_eq!((book.top_bids(10), book.top_asks(10)), before);
+        assert_eq!(book.last_update_id, 105);
+        assert_eq!((book.accepted_updates, book.rejected_updates), (1, 1));
 
-    client.run().await
-}
\ No newline at end of file
+        let result = book.apply_update(&update(106, 106, 105, &[("100", "x")], &[]));
+        assert!(matches!(result, Err(OrderBookError::InvalidLevel { .. })));
+        assert_eq!(book.quantity_at(BookSide::Bid, dec("100")), dec("1"));
+
+        // The sequence didn't advance, so the next good update still follows on
+        book.apply_update(&update(106, 106, 105, &[("100", "5")], &[])).unwrap();
+        assert_eq!(book.quantity_at(BookSide::Bid, dec("100")), dec("5"));
+    }
+
+    #[test]
+    fn apply_update_drops_invalid_level_covered_by_snapshot_as_stale() {
+        let mut book = snapshot_book();
+        let result = book.apply_update(&update(80, 90, 79, &[("abc", "1")], &[]));
+        assert_eq!(result, Err(OrderBookError::StaleUpdate { final_update_id: 90, last_update_id: 100 }));
+        assert_eq!(book.rejected_updates, 0);
+    }
+
+    #[test]
+    fn apply_update_rejects_sequence_errors() {
+        let mut book = snapshot_book();
+        assert_eq!(
+            book.apply_update(&update(102, 110, 101, &[], &[])),
+            Err(OrderBookError::SnapshotGap { first_update_id: 102, final_update_id: 110, last_update_id: 100 })
+        );
+        assert!(!book.synced);
+
+        let mut book = synced_book();
+        assert_eq!(
+            book.apply_update(&update(107, 108, 106, &[("100", "5")], &[])),
+            Err(OrderBookError::SequenceGap { prev_final_update_id: 106, last_update_id: 105 })
+        );
+        assert_eq!(book.quantity_at(BookSide::Bid, dec("100")), dec("1"));
+        assert_eq!(book.last_update_id, 105);
+    }
+
+    #[test]
+    fn apply_update_removes_zero_quantity_levels() {
+        let mut book = synced_book();
+        book.apply_update(&update(106, 106, 105, &[("100", "0")], &[("101", "0.000")])).unwrap();
+        assert_eq!(book.best_bid(), Some((dec("99"), dec("2"))));
+        assert_eq!(book.best_ask(), Some((dec("102"), dec("2"))));
+    }
+
+    #[test]
+    fn quantity_at_reads_the_given_book_side() {
+        let book = synced_book();
+        assert_eq!(book.quantity_at(BookSide::Bid, dec("99")), dec("2"));
+        assert_eq!(book.quantity_at(BookSide::Ask, dec("102")), dec("2"));
+        assert_eq!(book.quantity_at(BookSide::Ask, dec("99")), Decimal::ZERO);
+    }
+
+    #[test]
+    fn market_impact_walks_the_opposite_side() {
+        let book = synced_book();
+        assert_eq!(book.market_impact(Side::Buy, dec("3")), Some(dec("305") / dec("3")));
+        assert_eq!(book.market_impact(Side::Sell, dec("1")), Some(dec("100")));
+        assert_eq!(book.market_impact(Side::Sell, dec("3")), Some(dec("298") / dec("3")));
+        assert_eq!(book.market_impact(Side::Buy, dec("7")), None);
+        assert_eq!(book.market_impact(Side::Buy, Decimal::ZERO), None);
+    }
+
+    #[test]
+    fn depth_to_move_consumes_levels_inside_the_threshold() {
+        let book = synced_book();
+        // 1% above the 101 ask is 102.01: 101 and 102 must go
+        assert_eq!(book.depth_to_move(Side::Buy, dec("100")), Some(dec("3")));
+        // 1% below the 100 bid is exactly 99, which stays
+        assert_eq!(book.depth_to_move(Side::Sell, dec("100")), Some(dec("1")));
+        assert_eq!(book.depth_to_move(Side::Buy, Decimal::ZERO), Some(Decimal::ZERO));
+        assert_eq!(book.depth_to_move(Side::Buy, dec("1000")), None);
+        assert_eq!(book.depth_to_move(Side::Buy, dec("-1")), None);
+    }
+
+    #[test]
+    fn delta_feed_rebuilds_the_book() {
+        let source = synced_book();
+        let snapshot = OrderBookUpdateEvent::from_book(&source, usize::MAX);
+        let mut book = OrderBook::from_snapshot_event(&snapshot);
+        assert!(book.synced);
+        assert_eq!((book.top_bids(10), book.top_asks(10)), (source.top_bids(10), source.top_asks(10)));
+
+        let delta = |first, last, prev, bids: Vec<(Decimal, Decimal)>| OrderBookDeltaEvent {
+            symbol: "BTCUSDT".into(),
+            first_update_id: first,
+            final_update_id: last,
+            prev_final_update_id: prev,
+            bids,
+            asks: Vec::new(),
+        };
+        assert_eq!(
+            book.apply_delta(&delta(104, 105, 103, vec![(dec("100"), dec("9"))])),
+            Err(OrderBookError::StaleUpdate { final_update_id: 105, last_update_id: 105 })
+        );
+        assert_eq!(book.quantity_at(BookSide::Bid, dec("100")), dec("1"));
+
+        book.apply_delta(&delta(106, 106, 105, vec![(dec("100"), Decimal::ZERO)])).unwrap();
+        assert_eq!(book.best_bid(), Some((dec("99"), dec("2"))));
+        assert_eq!(
+            book.apply_delta(&delta(108, 108, 107, Vec::new())),
+            Err(OrderBookError::SequenceGap { prev_final_update_id: 107, last_update_id: 106 })
+        );
+    }
+
+    #[test]
+    fn book_tickers_below_high_water_are_discarded() {
+        let cache = MarketDataCache::new(&ClientConfig::default());
+        cache.batch_update_book_tickers(vec![ticker("BTCUSDT", 10, "100")]);
+        // The same update id can carry a new price (e.g. after a reconnect)
+        cache.batch_update_book_tickers(vec![ticker("BTCUSDT", 10, "101")]);
+        assert_eq!(cache.book_ticker("btcusdt").map(|t| t.bid_price), Some(dec("101")));
+
+        cache.batch_update_book_tickers(vec![ticker("BTCUSDT", 9, "99")]);
+        assert_eq!(cache.book_ticker("BTCUSDT").map(|t| t.bid_price), Some(dec("101")));
+
+        cache.batch_update_book_tickers(vec![ticker("BTCUSDT", 11, "102")]);
+        assert_eq!(cache.book_ticker("BTCUSDT").map(|t| t.bid_price), Some(dec("102")));
+    }
+
+    #[test]
+    fn caches_sharing_a_store_keep_markets_apart() {
+        let usd_m = MarketDataCache::new(&ClientConfig::default());
+        let coin_config = ClientConfig { market: Market::CoinM, ..ClientConfig::default() };
+        let coin_m = MarketDataCache::with_store(&coin_config, usd_m.market_store());
+        usd_m.batch_update_book_tickers(vec![ticker("BTCUSD_PERP", 5, "100")]);
+        coin_m.batch_update_book_tickers(vec![ticker("BTCUSD_PERP", 1, "200")]);
+
+        assert_eq!(usd_m.book_ticker("BTCUSD_PERP").map(|t| t.bid_price), Some(dec("100")));
+        assert_eq!(coin_m.book_ticker("BTCUSD_PERP").map(|t| t.bid_price), Some(dec("200")));
+        assert_eq!(
+            usd_m.book_ticker((Market::CoinM, "BTCUSD_PERP")).map(|t| t.bid_price),
+            Some(dec("200"))
+        );
+        // Only the cache's own market is replayed to its subscribers
+        let _events = usd_m.subscribe();
+        assert_eq!(usd_m.replay_book_tickers(), 1);
+    }
+}
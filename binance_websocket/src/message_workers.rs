@@ -0,0 +1,103 @@
+// Optional pool of tasks that parse frames and apply them to the cache, so the
+// read loop only does I/O. Frames are sharded by symbol: every frame of a
+// symbol goes to the same worker and is handled in arrival order, which keeps
+// its depth updates in sequence.
+use super::{flush_batch, frame_stream_name, handle_message, MarketDataCache};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{warn, Instrument};
+
+pub struct MessageWorkers {
+    senders: Vec<mpsc::Sender<(String, Option<u64>)>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MessageWorkers {
+    pub fn spawn(count: usize, queue_capacity: usize, cache: Arc<MarketDataCache>) -> Self {
+        let (senders, tasks) = (0..count)
+            .map(|_| {
+                let (tx, rx) = mpsc::channel(queue_capacity);
+                (tx, tokio::spawn(run_worker(rx, cache.clone()).in_current_span()))
+            })
+            .unzip();
+        Self { senders, tasks }
+    }
+
+    // Queue a frame on its symbol's worker. Waits while that worker's queue is
+    // full, so a pool that falls behind pushes back on the socket rather than
+    // buffering without bound.
+    pub async fn dispatch(&self, text: String, received_at_ms: Option<u64>) {
+        let shard = self.shard(&text);
+        if self.senders[shard].send((text, received_at_ms)).await.is_err() {
+            warn!("Message worker {} has stopped, frame dropped", shard);
+        }
+    }
+
+    // Combined frames name their stream before the payload, so the symbol is
+    // read without parsing the frame. All-market streams ("!bookTicker") are
+    // keyed by stream and stay whole on one worker; raw-mode frames carry no
+    // stream name and all share a worker.
+    fn shard(&self, text: &str) -> usize {
+        let key = frame_stream_name(text)
+            .map(|stream| stream.split('@').next().unwrap_or(stream))
+            .unwrap_or_default();
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.senders.len() as u64) as usize
+    }
+
+    // Close the queues and wait until every queued frame has been applied
+    pub async fn shutdown(self) {
+        drop(self.senders);
+        for task in self.tasks {
+            if let Err(e) = task.await {
+                warn!("Message worker ended abnormally: {}", e);
+            }
+        }
+    }
+}
+
+async fn run_worker(mut frames: mpsc::Receiver<(String, Option<u64>)>, cache: Arc<MarketDataCache>) {
+    let batch_size = cache.config.batch_size;
+    let mut book_ticker_batch = Vec::with_capacity(batch_size);
+    let mut trade_batch = Vec::with_capacity(batch_size);
+    let mut last_flush = tokio::time::Instant::now();
+
+    loop {
+        tokio::select! {
+            _ = flush_batch(
+                &mut book_ticker_batch,
+                &mut trade_batch,
+                &mut last_flush,
+                cache.clone(),
+            ) => {}
+
+            frame = frames.recv() => {
+                let Some((text, received_at_ms)) = frame else {
+                    break;
+                };
+                if let Err(e) = handle_message(
+                    &text,
+                    received_at_ms,
+                    &mut book_ticker_batch,
+                    &mut trade_batch,
+                    batch_size,
+                    cache.clone(),
+                ).await {
+                    warn!("Message worker failed to handle frame: {}", e);
+                }
+            }
+        }
+    }
+
+    // The pool is shutting down; apply what is still batched
+    if !book_ticker_batch.is_empty() {
+        cache.batch_update_book_tickers(book_ticker_batch);
+    }
+    if !trade_batch.is_empty() {
+        cache.batch_update_trades(trade_batch);
+    }
+}
@@ -0,0 +1,51 @@
+// OpenTelemetry export of the client's tracing spans over OTLP/HTTP. Configured
+// through the standard OTEL_* environment variables, and only switched on when
+// an OTLP endpoint is set, so a build with the feature logs exactly like one
+// without until a collector is configured.
+use super::WebSocketError;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::warn;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+// Either one enables export; the exporter reads them itself
+const ENDPOINT_VARS: &[&str] = &["OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "OTEL_EXPORTER_OTLP_ENDPOINT"];
+
+// Flushes buffered spans when dropped, so hold it until main returns
+pub struct OtelGuard(SdkTracerProvider);
+
+impl Drop for OtelGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            warn!("Failed to flush OpenTelemetry spans: {}", e);
+        }
+    }
+}
+
+// The span export layer and its guard; None when no endpoint is configured
+pub fn layer<S>() -> Result<Option<(impl Layer<S>, OtelGuard)>, WebSocketError>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    if !ENDPOINT_VARS.iter().any(|var| std::env::var_os(var).is_some()) {
+        return Ok(None);
+    }
+    let exporter = opentelemetry_otlp::SpanExporter::builder().with_http().build()?;
+
+    // Name the service after the binary unless the environment names it
+    let named_by_env = std::env::var_os("OTEL_SERVICE_NAME").is_some()
+        || std::env::var("OTEL_RESOURCE_ATTRIBUTES").is_ok_and(|attrs| attrs.contains("service.name="));
+    let mut resource = Resource::builder();
+    if !named_by_env {
+        resource = resource.with_service_name(env!("CARGO_PKG_NAME"));
+    }
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    Ok(Some((tracing_opentelemetry::layer().with_tracer(tracer), OtelGuard(provider))))
+}
@@ -0,0 +1,326 @@
+// Parquet output of captured trades and book tickers, rotated by time or row count.
+// Fed from the cache's market event broadcast so it never blocks the read path.
+use super::{unix_time_ms, BookTickerData, MarketEvent, TradeData, WebSocketError};
+use arrow_array::builder::{BooleanBuilder, Decimal128Builder, StringBuilder, UInt64Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::errors::ParquetError;
+use rust_decimal::Decimal;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, info, warn};
+
+// Binance quotes at most 8 decimal places, so prices and quantities fit exactly
+const DECIMAL_PRECISION: u8 = 38;
+const DECIMAL_SCALE: i8 = 8;
+// Rows buffered before being handed to the writer as one RecordBatch
+const RECORD_BATCH_ROWS: usize = 8192;
+// How often open files are checked against the rotation interval
+const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// A row type with a fixed Arrow schema
+pub trait ParquetRecord: Sized {
+    // File name prefix, e.g. "trades" for trades-<start_ms>.parquet
+    const PREFIX: &'static str;
+
+    fn schema() -> SchemaRef;
+
+    fn to_batch(rows: &[Self]) -> Result<RecordBatch, WebSocketError>;
+}
+
+// A book ticker stamped with the local time it was captured; the stream
+// payload itself carries no timestamp
+pub struct BookTickerSample {
+    captured_at_ms: u64,
+    ticker: BookTickerData,
+}
+
+fn decimal_field(name: &str) -> Field {
+    Field::new(name, DataType::Decimal128(DECIMAL_PRECISION, DECIMAL_SCALE), false)
+}
+
+fn decimal_column(values: impl Iterator<Item = Decimal>) -> Result<ArrayRef, WebSocketError> {
+    let mut builder = Decimal128Builder::new();
+    for mut value in values {
+        value.rescale(DECIMAL_SCALE as u32);
+        builder.append_value(value.mantissa());
+    }
+    let array = builder
+        .finish()
+        .with_precision_and_scale(DECIMAL_PRECISION, DECIMAL_SCALE)
+        .map_err(ParquetError::from)?;
+    Ok(Arc::new(array))
+}
+
+fn u64_column(values: impl Iterator<Item = u64>) -> ArrayRef {
+    let mut builder = UInt64Builder::new();
+    values.for_each(|v| builder.append_value(v));
+    Arc::new(builder.finish())
+}
+
+fn string_column<'a>(values: impl Iterator<Item = &'a str>) -> ArrayRef {
+    let mut builder = StringBuilder::new();
+    values.for_each(|v| builder.append_value(v));
+    Arc::new(builder.finish())
+}
+
+fn bool_column(values: impl Iterator<Item = bool>) -> ArrayRef {
+    let mut builder = BooleanBuilder::new();
+    values.for_each(|v| builder.append_value(v));
+    Arc::new(builder.finish())
+}
+
+impl ParquetRecord for TradeData {
+    const PREFIX: &'static str = "trades";
+
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("symbol", DataType::Utf8, false),
+            Field::new("event_time", DataType::UInt64, false),
+            Field::new("trade_time", DataType::UInt64, false),
+            Field::new("trade_id", DataType::UInt64, false),
+            decimal_field("price"),
+            decimal_field("quantity"),
+            Field::new("buyer_order_id", DataType::UInt64, false),
+            Field::new("seller_order_id", DataType::UInt64, false),
+            Field::new("is_buyer_market_maker", DataType::Boolean, false),
+        ]))
+    }
+
+    fn to_batch(rows: &[Self]) -> Result<RecordBatch, WebSocketError> {
+        let columns = vec![
+            string_column(rows.iter().map(|t| t.symbol.as_str())),
+            u64_column(rows.iter().map(|t| t.event_time)),
+            u64_column(rows.iter().map(|t| t.trade_time)),
+            u64_column(rows.iter().map(|t| t.trade_id)),
+            decimal_column(rows.iter().map(|t| t.price))?,
+            decimal_column(rows.iter().map(|t| t.quantity))?,
+            u64_column(rows.iter().map(|t| t.buyer_order_id)),
+            u64_column(rows.iter().map(|t| t.seller_order_id)),
+            bool_column(rows.iter().map(|t| t.is_buyer_market_maker)),
+        ];
+        Ok(RecordBatch::try_new(Self::schema(), columns).map_err(ParquetError::from)?)
+    }
+}
+
+impl ParquetRecord for BookTickerSample {
+    const PREFIX: &'static str = "book_tickers";
+
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("symbol", DataType::Utf8, false),
+            Field::new("captured_at", DataType::UInt64, false),
+            Field::new("update_id", DataType::UInt64, false),
+            decimal_field("bid_price"),
+            decimal_field("bid_qty"),
+            decimal_field("ask_price"),
+            decimal_field("ask_qty"),
+        ]))
+    }
+
+    fn to_batch(rows: &[Self]) -> Result<RecordBatch, WebSocketError> {
+        let columns = vec![
+            string_column(rows.iter().map(|s| s.ticker.symbol.as_str())),
+            u64_column(rows.iter().map(|s| s.captured_at_ms)),
+            u64_column(rows.iter().map(|s| s.ticker.update_id)),
+            decimal_column(rows.iter().map(|s| s.ticker.bid_price))?,
+            decimal_column(rows.iter().map(|s| s.ticker.bid_qty))?,
+            decimal_column(rows.iter().map(|s| s.ticker.ask_price))?,
+            decimal_column(rows.iter().map(|s| s.ticker.ask_qty))?,
+        ];
+        Ok(RecordBatch::try_new(Self::schema(), columns).map_err(ParquetError::from)?)
+    }
+}
+
+// Write rows as one complete Parquet file, <prefix>-<now_ms>.parquet in dir,
+// synced to disk before it appears under its final name. All or nothing: on
+// error the partial .tmp file is removed and no rows count as written.
+pub fn write_file<T: ParquetRecord>(dir: &Path, rows: &[T]) -> Result<PathBuf, WebSocketError> {
+    std::fs::create_dir_all(dir)?;
+    let final_path = dir.join(format!("{}-{}.parquet", T::PREFIX, unix_time_ms()));
+    let tmp_path = final_path.with_extension("tmp");
+    let result = (|| {
+        let mut writer = ArrowWriter::try_new(File::create(&tmp_path)?, T::schema(), None)?;
+        for chunk in rows.chunks(RECORD_BATCH_ROWS) {
+            writer.write(&T::to_batch(chunk)?)?;
+        }
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp_path, &final_path)?;
+        Ok(final_path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+// File currently being written; lives under a .tmp name until finished so
+// readers never pick up a partial file
+struct OpenFile {
+    writer: ArrowWriter<File>,
+    tmp_path: PathBuf,
+    final_path: PathBuf,
+    opened_at: tokio::time::Instant,
+    rows: usize,
+}
+
+// Buffers rows of one record type and writes them to a sequence of Parquet
+// files, starting a new file once the current one is old or large enough
+pub struct RotatingWriter<T: ParquetRecord> {
+    dir: PathBuf,
+    rotate_interval: Duration,
+    max_rows: usize,
+    pending: Vec<T>,
+    file: Option<OpenFile>,
+}
+
+impl<T: ParquetRecord> RotatingWriter<T> {
+    pub fn new(dir: PathBuf, rotate_interval: Duration, max_rows: usize) -> Self {
+        Self {
+            dir,
+            rotate_interval,
+            max_rows,
+            pending: Vec::with_capacity(RECORD_BATCH_ROWS),
+            file: None,
+        }
+    }
+
+    pub fn push(&mut self, row: T) -> Result<(), WebSocketError> {
+        self.pending.push(row);
+        let rows_in_file = self.file.as_ref().map_or(0, |f| f.rows);
+        if self.pending.len() >= RECORD_BATCH_ROWS || rows_in_file + self.pending.len() >= self.max_rows {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Hand buffered rows to the writer, rotating once the file reaches max_rows
+    fn flush(&mut self) -> Result<(), WebSocketError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let batch = T::to_batch(&self.pending)?;
+        self.pending.clear();
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(self.open()?),
+        };
+        file.writer.write(&batch)?;
+        file.rows += batch.num_rows();
+        if file.rows >= self.max_rows {
+            self.finish()?;
+        }
+        Ok(())
+    }
+
+    fn open(&self) -> Result<OpenFile, WebSocketError> {
+        std::fs::create_dir_all(&self.dir)?;
+        let final_path = self.dir.join(format!("{}-{}.parquet", T::PREFIX, unix_time_ms()));
+        let tmp_path = final_path.with_extension("tmp");
+        let writer = ArrowWriter::try_new(File::create(&tmp_path)?, T::schema(), None)?;
+        debug!("Opened Parquet file {}", tmp_path.display());
+        Ok(OpenFile {
+            writer,
+            tmp_path,
+            final_path,
+            opened_at: tokio::time::Instant::now(),
+            rows: 0,
+        })
+    }
+
+    // Rotate if the open file has been written to for longer than rotate_interval
+    pub fn rotate_if_due(&mut self) -> Result<(), WebSocketError> {
+        let due = self.file.as_ref().is_some_and(|f| f.opened_at.elapsed() >= self.rotate_interval);
+        if due {
+            self.flush()?;
+            self.finish()?;
+        } else if self.file.is_none() && !self.pending.is_empty() {
+            // Open the file now so its age counts from the first buffered rows
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Write out buffered rows and close the current file under its final name
+    pub fn finish(&mut self) -> Result<(), WebSocketError> {
+        self.flush()?;
+        let Some(file) = self.file.take() else {
+            return Ok(());
+        };
+        file.writer.close()?;
+        std::fs::rename(&file.tmp_path, &file.final_path)?;
+        info!("Wrote {} rows to {}", file.rows, file.final_path.display());
+        Ok(())
+    }
+}
+
+impl<T: ParquetRecord> Drop for RotatingWriter<T> {
+    // Background tasks are aborted on shutdown; finish the open file rather
+    // than leave a .tmp behind
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("Failed to finish {} Parquet file: {}", T::PREFIX, e);
+        }
+    }
+}
+
+// Writes trades and book tickers from the market event broadcast to Parquet
+pub struct ParquetSink {
+    trades: RotatingWriter<TradeData>,
+    book_tickers: RotatingWriter<BookTickerSample>,
+}
+
+impl ParquetSink {
+    pub fn new(dir: PathBuf, rotate_interval: Duration, max_rows: usize) -> Self {
+        Self {
+            trades: RotatingWriter::new(dir.clone(), rotate_interval, max_rows),
+            book_tickers: RotatingWriter::new(dir, rotate_interval, max_rows),
+        }
+    }
+
+    fn handle(&mut self, event: MarketEvent) -> Result<(), WebSocketError> {
+        match event {
+            MarketEvent::Trade(trade) => self.trades.push(trade),
+            MarketEvent::BookTicker(ticker) => self.book_tickers.push(BookTickerSample {
+                captured_at_ms: unix_time_ms(),
+                ticker,
+            }),
+            MarketEvent::OrderBookUpdate(_)
+            | MarketEvent::OrderBookSnapshot(_)
+            | MarketEvent::OrderBookDelta(_)
+            // Already written when it first arrived
+            | MarketEvent::StaleBookTicker(_)
+            | MarketEvent::Connected { .. }
+            | MarketEvent::Disconnected { .. }
+            | MarketEvent::Reconnecting { .. } => Ok(()),
+        }
+    }
+
+    pub async fn run(mut self, mut events: broadcast::Receiver<MarketEvent>) {
+        let mut rotation_check = tokio::time::interval(ROTATION_CHECK_INTERVAL);
+        loop {
+            let result = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => self.handle(event),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Parquet writer lagged, {} market events not written", skipped);
+                        Ok(())
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = rotation_check.tick() => self
+                    .trades
+                    .rotate_if_due()
+                    .and_then(|_| self.book_tickers.rotate_if_due()),
+            };
+            if let Err(e) = result {
+                warn!("Parquet write failed: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// Per-symbol 1-second trade aggregates, built from the cache's market event
+// broadcast and handed out over a channel so dashboards don't keep every trade.
+use super::{unix_time_ms, MarketEvent, Side, TradeData};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+// Bucket width, in milliseconds of exchange trade time
+const BUCKET_MS: u64 = 1000;
+// How often open buckets are checked against the wall clock, so quiet symbols
+// still emit once their second is over
+const FLUSH_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Trades of one symbol whose trade time falls in [start_ms, start_ms + 1000)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TradeBucket {
+    pub symbol: String,
+    pub start_ms: u64,
+    pub trade_count: u64,
+    pub volume: Decimal,
+    // Volume by aggressor side
+    pub buy_volume: Decimal,
+    pub sell_volume: Decimal,
+    pub vwap: Decimal,
+}
+
+// Running totals for the open bucket of one symbol
+struct OpenBucket {
+    start_ms: u64,
+    trade_count: u64,
+    volume: Decimal,
+    buy_volume: Decimal,
+    notional: Decimal,
+}
+
+impl OpenBucket {
+    fn new(start_ms: u64) -> Self {
+        Self {
+            start_ms,
+            trade_count: 0,
+            volume: Decimal::ZERO,
+            buy_volume: Decimal::ZERO,
+            notional: Decimal::ZERO,
+        }
+    }
+
+    fn add(&mut self, trade: &TradeData) {
+        self.trade_count += 1;
+        self.volume += trade.quantity;
+        self.notional += trade.price * trade.quantity;
+        if trade.aggressor_side() == Side::Buy {
+            self.buy_volume += trade.quantity;
+        }
+    }
+
+    fn close(self, symbol: String) -> TradeBucket {
+        let vwap = if self.volume.is_zero() {
+            Decimal::ZERO
+        } else {
+            self.notional / self.volume
+        };
+        TradeBucket {
+            symbol,
+            start_ms: self.start_ms,
+            trade_count: self.trade_count,
+            volume: self.volume,
+            buy_volume: self.buy_volume,
+            sell_volume: self.volume - self.buy_volume,
+            vwap,
+        }
+    }
+}
+
+// Groups trades into 1-second buckets per symbol. A bucket is emitted when a
+// trade from a later second arrives for its symbol, or once the wall clock is
+// past its end. Trades arriving after their second was emitted start a new
+// bucket for that second rather than being dropped.
+pub struct TradeBucketizer {
+    open: HashMap<String, OpenBucket>,
+    tx: mpsc::Sender<TradeBucket>,
+}
+
+impl TradeBucketizer {
+    pub fn new(tx: mpsc::Sender<TradeBucket>) -> Self {
+        Self {
+            open: HashMap::new(),
+            tx,
+        }
+    }
+
+    // Returns false once the receiving side is gone
+    async fn add(&mut self, trade: TradeData) -> bool {
+        let start_ms = trade.trade_time - trade.trade_time % BUCKET_MS;
+        let symbol = trade.symbol.to_uppercase();
+        if self.open.get(&symbol).is_some_and(|bucket| bucket.start_ms != start_ms) {
+            if let Some(bucket) = self.open.remove(&symbol) {
+                if self.tx.send(bucket.close(symbol.clone())).await.is_err() {
+                    return false;
+                }
+            }
+        }
+        self.open
+            .entry(symbol)
+            .or_insert_with(|| OpenBucket::new(start_ms))
+            .add(&trade);
+        true
+    }
+
+    // Emit every bucket whose second ended before `now_ms`
+    async fn flush_before(&mut self, now_ms: u64) -> bool {
+        let due: Vec<_> = self.open
+            .extract_if(|_, bucket| bucket.start_ms + BUCKET_MS <= now_ms)
+            .collect();
+        for (symbol, bucket) in due {
+            if self.tx.send(bucket.close(symbol)).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub async fn run(mut self, mut events: broadcast::Receiver<MarketEvent>) {
+        let mut flush_check = tokio::time::interval(FLUSH_CHECK_INTERVAL);
+        loop {
+            let open = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(MarketEvent::Trade(trade)) => self.add(trade).await,
+                    Ok(_) => true,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Trade bucketizer lagged, {} market events missed", skipped);
+                        true
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = flush_check.tick() => self.flush_before(unix_time_ms()).await,
+            };
+            if !open {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+// Top-of-book fan-out as one small UDP datagram per book change, for local
+// consumers that can't afford TCP or WebSocket framing. Fed from the cache's
+// market event broadcast so it never blocks the read path; a slow or absent
+// receiver only costs dropped datagrams.
+//
+// Packet layout (version 1), little-endian, 3 + n + 48 bytes:
+//   0      u8      version (1)
+//   1      u8      source: 0 = depth book, 1 = bookTicker
+//   2      u8      n, length of the symbol
+//   3      n       symbol, ASCII (the book's cache key, e.g. BTCUSDT)
+//   3+n    u64     update id (lastUpdateId of the book, or the bookTicker's u)
+//   11+n   f64     best bid price
+//   19+n   f64     best bid quantity
+//   27+n   f64     best ask price
+//   35+n   f64     best ask quantity
+//   43+n   u64     send time, unix ms
+// Books with an empty side send nothing.
+use super::{unix_time_ms, MarketEvent, OrderBook, TopOfBook};
+use rust_decimal::prelude::ToPrimitive;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error, info, warn};
+
+const PACKET_VERSION: u8 = 1;
+const SOURCE_BOOK: u8 = 0;
+const SOURCE_BOOK_TICKER: u8 = 1;
+// Longer keys are truncated; real symbols and SYMBOL@STREAM keys are far shorter
+const MAX_SYMBOL_LEN: usize = u8::MAX as usize;
+
+pub struct UdpTopOfBookSink {
+    target: SocketAddr,
+    // Replicas of delta-broadcast books, since deltas alone don't carry the top.
+    // Dropped on a sequence break until the book's next snapshot event.
+    replicas: HashMap<String, OrderBook>,
+    packet: Vec<u8>,
+}
+
+impl UdpTopOfBookSink {
+    pub fn new(target: SocketAddr) -> Self {
+        Self {
+            target,
+            replicas: HashMap::new(),
+            packet: Vec::with_capacity(3 + MAX_SYMBOL_LEN + 48),
+        }
+    }
+
+    // The top of book an event leaves its symbol at, with the packet source
+    fn top_of_book(&mut self, event: MarketEvent) -> Option<(String, u8, TopOfBook)> {
+        match event {
+            MarketEvent::OrderBookUpdate(update) => {
+                let (&(bid_price, bid_qty), &(ask_price, ask_qty)) = (update.bids.first()?, update.asks.first()?);
+                let top = TopOfBook { bid_price, bid_qty, ask_price, ask_qty, last_update_id: update.last_update_id };
+                Some((update.symbol, SOURCE_BOOK, top))
+            }
+            MarketEvent::OrderBookSnapshot(snapshot) => {
+                let book = OrderBook::from_snapshot_event(&snapshot);
+                let top = book.top_of_book();
+                self.replicas.insert(snapshot.symbol.clone(), book);
+                Some((snapshot.symbol, SOURCE_BOOK, top?))
+            }
+            MarketEvent::OrderBookDelta(delta) => {
+                let book = self.replicas.get_mut(&delta.symbol)?;
+                if let Err(e) = book.apply_delta(&delta) {
+                    debug!("UDP sink dropping replica of {} until its next snapshot: {}", delta.symbol, e);
+                    self.replicas.remove(&delta.symbol);
+                    return None;
+                }
+                Some((delta.symbol, SOURCE_BOOK, book.top_of_book()?))
+            }
+            MarketEvent::BookTicker(ticker) => {
+                let top = ticker.top_of_book();
+                Some((ticker.symbol.to_uppercase(), SOURCE_BOOK_TICKER, top))
+            }
+            _ => None,
+        }
+    }
+
+    fn encode(&mut self, symbol: &str, source: u8, top: &TopOfBook) {
+        let symbol = &symbol.as_bytes()[..symbol.len().min(MAX_SYMBOL_LEN)];
+        let packet = &mut self.packet;
+        packet.clear();
+        packet.push(PACKET_VERSION);
+        packet.push(source);
+        packet.push(symbol.len() as u8);
+        packet.extend_from_slice(symbol);
+        packet.extend_from_slice(&top.last_update_id.to_le_bytes());
+        for value in [top.bid_price, top.bid_qty, top.ask_price, top.ask_qty] {
+            packet.extend_from_slice(&value.to_f64().unwrap_or(f64::NAN).to_le_bytes());
+        }
+        packet.extend_from_slice(&unix_time_ms().to_le_bytes());
+    }
+
+    pub async fn run(mut self, mut events: broadcast::Receiver<MarketEvent>) {
+        let bind_addr: SocketAddr = if self.target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = match UdpSocket::bind(bind_addr).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("UDP top-of-book sink disabled, failed to bind: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = socket.connect(self.target).await {
+            error!("UDP top-of-book sink disabled, failed to connect to {}: {}", self.target, e);
+            return;
+        }
+        info!("Sending top of book over UDP to {}", self.target);
+
+        let mut send_failed = false;
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("UDP top-of-book sink lagged, {} market events missed", skipped);
+                    // Deltas were missed, so the replicas can't be trusted
+                    self.replicas.clear();
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let Some((symbol, source, top)) = self.top_of_book(event) else {
+                continue;
+            };
+            self.encode(&symbol, source, &top);
+            // Nobody listening (ICMP port unreachable) shows up as a send error;
+            // warn once per run of failures rather than per packet
+            match socket.send(&self.packet).await {
+                Ok(_) => send_failed = false,
+                Err(e) if !send_failed => {
+                    warn!("UDP top-of-book send to {} failed: {}", self.target, e);
+                    send_failed = true;
+                }
+                Err(_) => {}
+            }
+        }
+    }
+}